@@ -1,23 +1,47 @@
-// TODO: Implement --preserve-filenames
-//       This would preserve the filenames that are given to the files on the
-//       given website. It can be accomplished, by using their API.
-//       Example API URLs:
-// 4plebs: https://archive.4plebs.org/_/api/chan/thread?board=x&num=32661196
-//  4chan: https://a.4cdn.org/po/thread/570368.json
-
 use futures::stream::StreamExt;
+use regex::Regex;
 use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
     env,
+    fmt,
     fs::create_dir_all,
-    io::Write,
+    io::{Read, Write},
     path::{Path, PathBuf},
-    sync::{Mutex, Once},
-    thread,
-    time::{Duration, Instant},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+        MutexGuard,
+        Once,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, Context, Error, Result};
-use chan_downloader::{get_image_links, get_page_content, get_thread_info, save_image};
+use chan_downloader::{
+    catalog::{self, ThreadFilter},
+    config::{Config, Entry},
+    dedup::{DedupIndex, LinkMode},
+    download_file,
+    file_md5,
+    filter::{parse_size, AspectRatio, MediaFilter, PostFilter, PostRule},
+    get_page_content,
+    get_page_content_since,
+    imageboard::{rename_taken_links, reserve_name, FourChan, Imageboard, Registry},
+    ratelimit::{parse_rate, RateLimiter},
+    retry::RetryPolicy,
+    schedule::{parse_duration, AdaptiveInterval},
+    state::{migrate_legacy_files, thread_key, DownloadState, Record, SavedThread, ThreadIndex},
+    template::{DirectoryTemplate, FilenameTemplate, ThreadContext},
+    verify::{verify_directory, Problem},
+    Error as DownloadError,
+    Link,
+    PageContent,
+    Post,
+    Thread,
+};
 use clap::{
     crate_authors,
     crate_description,
@@ -26,17 +50,25 @@ use clap::{
     AppSettings,
     Arg,
     ArgAction,
+    ArgMatches,
     ColorChoice,
     Command,
     ValueHint,
 };
 use env_logger::fmt::Color as LogColor;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{error, info, LevelFilter};
-use once_cell::sync::Lazy;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn, LevelFilter};
 use reqwest::Client;
+use tokio::{sync::Semaphore, task::JoinHandle};
+
+/// Exit code used when the thread 404'd, or has been archived or closed
+const EXIT_THREAD_ENDED: u8 = 3;
 
-static DOWNLOADED_FILES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Default requests per second to the API and the pages of each host
+const DEFAULT_RATE_LIMIT: f64 = 1.0;
+
+/// Default file downloads per second to each host
+const DEFAULT_MEDIA_RATE_LIMIT: f64 = 10.0;
 
 /// Run `initialize_logging` one time
 ///
@@ -44,51 +76,681 @@ static DOWNLOADED_FILES: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec:
 /// but this is a precaution
 static ONCE: Once = Once::new();
 
-fn main() -> Result<()> {
+/// Time between each check of the config file of the daemon for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
     let matches = build_app().get_matches();
     let verbosity = matches.get_one::<u8>("verbose").expect("Count always defaulted");
 
     initialize_logging(*verbosity);
 
-    let thread = matches
-        .get_one::<String>("thread")
-        .context("failed to get 'thread' value")?;
-    let output = matches
-        .get_one::<String>("output")
-        .map_or_else(|| String::from("downloads"), Clone::clone);
-    let reload = matches.contains_id("reload");
-    let interval = matches.get_one::<u64>("interval").unwrap_or(&5_u64);
-    let limit = matches.get_one::<u64>("limit").unwrap_or(&120_u64);
-    let concurrent = matches.get_one::<usize>("concurrent").unwrap_or(&2_usize);
+    let client = Client::builder().user_agent("reqwest").build()?;
 
-    info!("Downloading images from {} to {}", thread, output);
+    if let Some(("daemon", daemon_matches)) = matches.subcommand() {
+        let config = daemon_matches
+            .get_one::<PathBuf>("config")
+            .context("failed to get 'config' value")?;
+        return daemon(config, &client).await;
+    }
 
-    let directory = create_directory(thread, &output)?;
+    let entry = entry_from_matches(&matches)?;
+    let limiters = Limiters::new(
+        matches.get_one::<f64>("rate_limit").copied(),
+        matches.get_one::<f64>("media_rate_limit").copied(),
+    );
 
-    let start = Instant::now();
-    let wait_time = Duration::from_secs(60 * interval);
-    let limit_time = if reload {
-        Duration::from_secs(60 * limit)
+    if let Some(("verify", verify_matches)) = matches.subcommand() {
+        let directory = verify_matches
+            .get_one::<PathBuf>("directory")
+            .context("failed to get 'directory' value")?;
+        let url = verify_matches.get_one::<String>("url").map(String::as_str);
+        let repair = Repair {
+            problems: verify_matches.contains_id("repair"),
+            deleted:  verify_matches.contains_id("restore_deleted"),
+        };
+        let offline = verify_matches.contains_id("offline");
+        let options = Options::new(&entry, limiters, MultiProgress::new());
+        return verify(directory, url, repair, offline, &client, &options).await;
+    }
+
+    let options = entry_options(&entry, &limiters, &MultiProgress::new(), &mut Indexes::default())?;
+    run_entry(&entry, &client, Arc::new(options)).await
+}
+
+/// Returns the entry of the threads or of the board given on the command
+/// line
+fn entry_from_matches(matches: &ArgMatches) -> Result<Entry> {
+    let mut threads: Vec<String> = matches
+        .get_many::<String>("thread")
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    if let Some(input_file) = matches.get_one::<String>("input_file") {
+        threads.extend(read_thread_list(input_file)?);
+    }
+    let mut seen = HashSet::new();
+    threads.retain(|thread| seen.insert(thread.clone()));
+    let regex = |id| {
+        matches
+            .get_one::<Regex>(id)
+            .map(|regex| regex.as_str().to_owned())
+    };
+    let list = |id| {
+        matches
+            .get_many::<String>(id)
+            .map(|values| values.cloned().collect())
+    };
+    Ok(Entry {
+        thread:             threads,
+        board:              matches.get_one::<String>("board").cloned(),
+        subject:            regex("subject"),
+        comment:            regex("comment"),
+        output:             matches.get_one::<String>("output").cloned(),
+        preserve_filenames: Some(matches.contains_id("preserve_filenames")),
+        filename_template:  matches.get_one::<FilenameTemplate>("filename_template").cloned(),
+        dir_template:       matches.get_one::<DirectoryTemplate>("dir_template").cloned(),
+        reload:             Some(matches.contains_id("reload")),
+        follow:             Some(matches.contains_id("follow")),
+        interval:           matches.get_one::<Duration>("interval").copied(),
+        adaptive:           Some(matches.contains_id("adaptive")),
+        min_interval:       matches.get_one::<Duration>("min_interval").copied(),
+        max_interval:       matches.get_one::<Duration>("max_interval").copied(),
+        limit:              matches.get_one::<Duration>("limit").copied(),
+        concurrent:         matches.get_one::<u64>("concurrent").map(|&n| n as usize),
+        retries:            matches.get_one::<u32>("retries").copied(),
+        retry_budget:       matches.get_one::<u32>("retry_budget").copied(),
+        dedup:              matches.get_one::<LinkMode>("dedup").copied(),
+        ext:                list("ext"),
+        exclude_ext:        list("exclude_ext"),
+        min_size:           matches.get_one::<u64>("min_size").copied(),
+        max_size:           matches.get_one::<u64>("max_size").copied(),
+        min_width:          matches.get_one::<u32>("min_width").copied(),
+        max_width:          matches.get_one::<u32>("max_width").copied(),
+        min_height:         matches.get_one::<u32>("min_height").copied(),
+        max_height:         matches.get_one::<u32>("max_height").copied(),
+        aspect_ratio:       matches
+            .get_many::<AspectRatio>("aspect_ratio")
+            .map(|ratios| ratios.copied().collect()),
+        include_posts:      matches
+            .get_many::<PostRule>("include_posts")
+            .map(|rules| rules.cloned().collect()),
+        exclude_posts:      matches
+            .get_many::<PostRule>("exclude_posts")
+            .map(|rules| rules.cloned().collect()),
+    })
+}
+
+/// Returns the options of the entry. Entries saving to the same output
+/// directory share its index of threads, and all the entries share the index
+/// of the downloaded files.
+fn entry_options(
+    entry: &Entry,
+    limiters: &Limiters,
+    progress: &MultiProgress,
+    indexes: &mut Indexes,
+) -> Result<Options> {
+    let mut options = Options::new(entry, limiters.clone(), progress.clone());
+    let root = env::current_dir()?.join(entry_output(entry));
+    if options.dedup != LinkMode::Off {
+        let index = match &indexes.dedup {
+            Some(index) => Arc::clone(index),
+            None => {
+                let path = DedupIndex::default_path().ok_or_else(|| {
+                    anyhow!("no data directory to keep the index of the downloaded files in")
+                })?;
+                let index = DedupIndex::open(&path)
+                    .with_context(|| format!("failed to load the index {}", path.display()))?;
+                Arc::clone(indexes.dedup.insert(Arc::new(Mutex::new(index))))
+            },
+        };
+        lock(&index)
+            .add_directory(&root)
+            .with_context(|| format!("failed to index {}", root.display()))?;
+        options.index = Some(index);
+    }
+    options.threads = Some(shared_index(&mut indexes.threads, &root, ThreadIndex::open)?);
+    options.reserved = Arc::clone(&indexes.reserved);
+    Ok(options)
+}
+
+/// Rate limiters shared by the entries. The files come from the CDN of the
+/// sites, which allows more requests than their API.
+#[derive(Clone)]
+struct Limiters {
+    pages: RateLimiter,
+    media: RateLimiter,
+}
+
+impl Limiters {
+    fn new(rate: Option<f64>, media_rate: Option<f64>) -> Self {
+        Self {
+            pages: RateLimiter::new(rate.unwrap_or(DEFAULT_RATE_LIMIT), 1),
+            media: RateLimiter::new(media_rate.unwrap_or(DEFAULT_MEDIA_RATE_LIMIT), 1),
+        }
+    }
+}
+
+/// Indexes shared by the entries
+#[derive(Default)]
+struct Indexes {
+    /// Files downloaded to all the output directories, opened by the first
+    /// entry with deduplication
+    dedup:    Option<Arc<Mutex<DedupIndex>>>,
+    /// Thread directories, by output directory
+    threads:  HashMap<PathBuf, Arc<Mutex<ThreadIndex>>>,
+    /// Paths of the downloads in progress
+    reserved: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Returns the index of the output directory, opening it the first time
+fn shared_index<T>(
+    indexes: &mut HashMap<PathBuf, Arc<Mutex<T>>>,
+    root: &Path,
+    open: impl FnOnce(&Path) -> Result<T, DownloadError>,
+) -> Result<Arc<Mutex<T>>> {
+    if let Some(index) = indexes.get(root) {
+        return Ok(Arc::clone(index));
+    }
+    let index = open(root).with_context(|| format!("failed to load the index of {}", root.display()))?;
+    let index = Arc::new(Mutex::new(index));
+    indexes.insert(root.to_owned(), Arc::clone(&index));
+    Ok(index)
+}
+
+/// Returns the output directory of the entry
+fn entry_output(entry: &Entry) -> &str {
+    entry.output.as_deref().unwrap_or("downloads")
+}
+
+/// Returns how the threads of the entry are reloaded. The threads of a board
+/// are always reloaded.
+fn entry_watch(entry: &Entry) -> Watch {
+    let reload = entry.board.is_some() || entry.reload.unwrap_or(true);
+    let limit = entry.limit.unwrap_or(Duration::from_secs(120 * 60));
+    let schedule = if entry.adaptive == Some(true) {
+        AdaptiveInterval::new(
+            entry.min_interval.unwrap_or(Duration::from_secs(30)),
+            entry.max_interval.unwrap_or(Duration::from_secs(10 * 60)),
+        )
+    } else {
+        AdaptiveInterval::fixed(entry.interval.unwrap_or(Duration::from_secs(5 * 60)))
+    };
+    let now = Instant::now();
+    let limit = if reload { limit } else { Duration::ZERO };
+    Watch {
+        reload,
+        follow: entry.board.is_none() && entry.follow == Some(true),
+        schedule,
+        // A limit too long for an `Instant` never ends
+        deadline: now
+            .checked_add(limit)
+            .unwrap_or_else(|| now + Duration::from_secs(u64::from(u32::MAX))),
+    }
+}
+
+/// Downloads the threads of the entry, or watches the catalog of its board
+async fn run_entry(entry: &Entry, client: &Client, options: Arc<Options>) -> Result<ExitCode> {
+    let output = entry_output(entry);
+    let watch = entry_watch(entry);
+    if let Some(board) = &entry.board {
+        let filter = ThreadFilter::new(
+            entry.subject.as_deref().map(Regex::new).transpose()?,
+            entry.comment.as_deref().map(Regex::new).transpose()?,
+        );
+        return watch_catalog(board, &filter, output, client, options, watch).await;
+    }
+
+    let threads = &entry.thread;
+    if threads.len() == 1 {
+        let end = watch_thread(&threads[0], output, client, &options, watch).await?;
+        return Ok(exit_code(end));
+    }
+    if threads.is_empty() {
+        return Err(anyhow!("no thread to download"));
+    }
+
+    let results = futures::future::join_all(
+        threads
+            .iter()
+            .map(|thread| watch_thread(thread, output, client, &options, watch.clone())),
+    )
+    .await;
+    let mut failed = false;
+    let mut all_ended = true;
+    for (thread, result) in threads.iter().zip(results) {
+        match result {
+            Ok(end) => all_ended &= end.is_some(),
+            Err(err) => {
+                eprintln!("Error: failed to download {}: {}", thread, err);
+                failed = true;
+            },
+        }
+    }
+    Ok(if failed {
+        ExitCode::FAILURE
+    } else if all_ended {
+        ExitCode::from(EXIT_THREAD_ENDED)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Watches the entries of the config file until interrupted. The file is
+/// checked for changes, to start the new and edited entries and to stop the
+/// removed ones.
+async fn daemon(path: &Path, client: &Client) -> Result<ExitCode> {
+    let mut config = Config::load(path).with_context(|| format!("failed to load {}", path.display()))?;
+    let mut modified = modified_time(path);
+    let limiters = Limiters::new(config.rate_limit, config.media_rate_limit);
+    let progress = MultiProgress::new();
+    let mut indexes = Indexes::default();
+    // Entries that ended are kept, so they only start again once edited
+    let mut running: Vec<(Entry, Option<JoinHandle<()>>)> = Vec::new();
+    loop {
+        running.retain(|(entry, task)| {
+            if config.entries.contains(entry) {
+                return true;
+            }
+            if let Some(task) = task {
+                task.abort();
+            }
+            println!("Stopped watching {}", entry.label());
+            false
+        });
+        for entry in &config.entries {
+            if running.iter().any(|(running, _)| running == entry) {
+                continue;
+            }
+            println!("Watching {}", entry.label());
+            let task = match entry_options(entry, &limiters, &progress, &mut indexes) {
+                Ok(options) => {
+                    let (entry, client) = (entry.clone(), client.clone());
+                    Some(tokio::spawn(async move {
+                        match run_entry(&entry, &client, Arc::new(options)).await {
+                            Ok(_) => println!("Done watching {}", entry.label()),
+                            Err(err) => eprintln!("Error: failed to watch {}: {}", entry.label(), err),
+                        }
+                    }))
+                },
+                Err(err) => {
+                    eprintln!("Error: failed to watch {}: {}", entry.label(), err);
+                    None
+                },
+            };
+            running.push((entry.clone(), task));
+        }
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(CONFIG_POLL_INTERVAL) => {},
+        }
+        let current = modified_time(path);
+        if current == modified {
+            continue;
+        }
+        modified = current;
+        match Config::load(path) {
+            Ok(reloaded) => {
+                info!("Reloaded {}", path.display());
+                if (reloaded.rate_limit, reloaded.media_rate_limit)
+                    != (config.rate_limit, config.media_rate_limit)
+                {
+                    warn!("The rate limits only change when the daemon restarts");
+                }
+                config = reloaded;
+            },
+            Err(err) => {
+                error!("Failed to reload {}: {}", path.display(), err);
+                eprintln!(
+                    "Error: failed to reload {}, keeping the previous config: {}",
+                    path.display(),
+                    err
+                );
+            },
+        }
+    }
+
+    for (_, task) in running {
+        if let Some(task) = task {
+            task.abort();
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Returns the time the file was last modified, if it can be read
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Returns the exit code of a run that downloaded a single thread
+fn exit_code(end: Option<ThreadEnd>) -> ExitCode {
+    match end {
+        Some(_) => ExitCode::from(EXIT_THREAD_ENDED),
+        None => ExitCode::SUCCESS,
+    }
+}
+
+/// Returns the thread urls listed in the file, or in the standard input for
+/// `-`, one per line. Empty lines and lines starting with `#` are skipped.
+fn read_thread_list(path: &str) -> Result<Vec<String>> {
+    let content = if path == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
     } else {
-        Duration::from_secs(0)
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?
     };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+/// How the threads are reloaded
+#[derive(Clone)]
+struct Watch {
+    reload:   bool,
+    /// Follow the next thread of a general when the thread ends
+    follow:   bool,
+    schedule: AdaptiveInterval,
+    /// No reload starts after the deadline
+    deadline: Instant,
+}
+
+/// Downloads the thread, and keeps reloading it until the deadline or until
+/// it ends when `watch.reload` is set. Returns how the thread ended, if it did
+async fn watch_thread(
+    thread: &str,
+    output: &str,
+    client: &Client,
+    options: &Options,
+    mut watch: Watch,
+) -> Result<Option<ThreadEnd>> {
+    let reload = watch.reload;
+    let mut thread = thread.to_owned();
+    let (mut directory, mut state) = open_thread(&thread, output, client, options).await?;
     loop {
         let load_start = Instant::now();
-        explore_thread(thread, &directory, *concurrent).unwrap();
-        let runtime = start.elapsed();
+        if state.end.is_none() {
+            if let Err(err) = explore_thread(&thread, &directory, client, options, &mut state).await {
+                if !reload {
+                    return Err(err);
+                }
+                error!("Failed to explore {}: {}", thread, err);
+                eprintln!("Error: {}", err);
+            }
+        }
+        if watch.follow && state.next.is_none() && (state.end.is_some() || state.bump_limit) {
+            state.next = find_next_thread(&thread, state.op.as_ref(), client, options).await;
+        }
+        // Without reload, an archived thread is simply downloaded once
+        if let Some(end) = state
+            .end
+            .filter(|end| reload || matches!(end, ThreadEnd::NotFound))
+        {
+            if !state.failed.is_empty() {
+                info!("Final sweep of {} failed files", state.failed.len());
+                let failed = std::mem::take(&mut state.failed);
+                download_links(failed, &directory, client, options, &state.downloads).await?;
+            }
+            if let Some(next) = state.next.take() {
+                println!("Thread {} {}, following {}", thread, end, next);
+                let (next_directory, next_state) = open_thread(&next, output, client, options).await?;
+                link_directories(&directory, &next_directory);
+                thread = next;
+                directory = next_directory;
+                state = next_state;
+                continue;
+            }
+            if !watch.follow || Instant::now() > watch.deadline {
+                println!("Thread {} {}, stopping.", thread, end);
+                return Ok(Some(end));
+            }
+            info!("Thread {} {}, waiting for the next thread", thread, end);
+        }
         let load_runtime = load_start.elapsed();
-        if runtime > limit_time {
+        if Instant::now() > watch.deadline {
             info!("Runtime exceeded, exiting.");
             break;
         };
+        let wait_time = watch.schedule.next(state.new_posts);
         if let Some(remaining) = wait_time.checked_sub(load_runtime) {
             info!("Schedule slice has time left over; sleeping for {:?}", remaining);
-            thread::sleep(remaining);
+            tokio::time::sleep(remaining).await;
         }
         info!("Downloader executed one more time for {:?}", load_runtime);
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// Creates the directory of the thread and loads its download state
+async fn open_thread(
+    thread: &str,
+    output: &str,
+    client: &Client,
+    options: &Options,
+) -> Result<(PathBuf, ThreadState)> {
+    info!("Downloading images from {} to {}", thread, output);
+
+    let directory = create_directory(thread, output, client, options).await?;
+    let downloads = open_state(thread, &directory)?;
+    info!(
+        "{} files previously downloaded to {}",
+        downloads.len(),
+        directory.display()
+    );
+    Ok((directory, ThreadState::new(downloads)))
+}
+
+/// Loads the download state of the thread in its directory, taking over the
+/// files of older versions
+fn open_state(thread_link: &str, directory: &Path) -> Result<DownloadState> {
+    let registry = Registry::default();
+    let (board, thread) = registry.resolve(thread_link)?;
+    let key = thread_key(board.name(), &thread);
+    migrate_legacy_files(directory, &key, &board.page_url(&thread))
+        .with_context(|| format!("failed to migrate the files of {}", directory.display()))?;
+    DownloadState::open(directory, &key)
+        .with_context(|| format!("failed to load the download state of {}", directory.display()))
+}
+
+/// Returns the names of the files recorded by the threads saved to the
+/// directory, with the url of their file, which the files of other urls must
+/// not overwrite
+fn taken_names(directory: &Path, downloads: &DownloadState) -> HashMap<String, String> {
+    let states = DownloadState::open_all(directory).unwrap_or_else(|err| {
+        warn!(
+            "Failed to load the download states of {}: {}",
+            directory.display(),
+            err
+        );
+        Vec::new()
+    });
+    states
+        .iter()
+        .filter(|state| state.path() != downloads.path())
+        .flat_map(DownloadState::records)
+        // The records of the thread itself are the freshest
+        .chain(downloads.records())
+        .map(|record| (record.path.clone(), record.url.clone()))
+        .collect()
+}
+
+/// Returns the url of the thread continuing the general thread, found in the
+/// catalog of its board
+async fn find_next_thread(
+    thread_link: &str,
+    op: Option<&Post>,
+    client: &Client,
+    options: &Options,
+) -> Option<String> {
+    let registry = Registry::default();
+    let (board, thread) = registry.resolve(thread_link).ok()?;
+    let catalog_link = match board.catalog_url(&thread.board) {
+        Some(catalog_link) => catalog_link,
+        None => {
+            warn!("{} has no catalog to find the next thread in", board.name());
+            return None;
+        },
+    };
+    // A thread that 404'd before its first pass is only known by its number
+    let previous = op.cloned().unwrap_or(Post {
+        no:            u64::from(thread.id),
+        time:          0,
+        name:          None,
+        tripcode:      None,
+        id:            None,
+        subject:       None,
+        comment:       None,
+        plain_comment: false,
+        file:          None,
+        archived:      false,
+        closed:        false,
+        bump_limit:    false,
+    });
+
+    let retry = options.retry.clone();
+    let content = retry.run(|| async {
+        options.limiter.acquire(&catalog_link).await;
+        get_page_content(&catalog_link, client).await
+    });
+    let ops = match content.await.map(|content| board.get_catalog(&content)) {
+        Ok(Ok(ops)) => ops,
+        Ok(Err(err)) => {
+            error!("Failed to parse the content of {}: {}", catalog_link, err);
+            return None;
+        },
+        Err(err) => {
+            error!("Failed to get content from {}: {}", catalog_link, err);
+            return None;
+        },
+    };
+    let next = catalog::find_next_thread(&previous, &ops)?;
+    let next = Thread {
+        board: thread.board.clone(),
+        id:    u32::try_from(next.no).ok()?,
+    };
+    info!("Found the next thread of {}: {}", thread_link, next.id);
+    Some(board.page_url(&next))
+}
+
+/// Links the directories of a thread and of the thread following it
+fn link_directories(previous: &Path, next: &Path) {
+    // Both threads are saved to the same directory in flat layouts
+    if previous == next {
+        return;
+    }
+    #[cfg(unix)]
+    for (link, target) in [(previous.join("next"), next), (next.join("previous"), previous)] {
+        if let Err(err) = std::os::unix::fs::symlink(target, &link) {
+            warn!(
+                "Failed to link {} to {}: {}",
+                link.display(),
+                target.display(),
+                err
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (previous, next);
+}
+
+/// Polls the catalog of the board, and watches every thread selected by the
+/// filter until the deadline
+async fn watch_catalog(
+    board: &str,
+    filter: &ThreadFilter,
+    output: &str,
+    client: &Client,
+    options: Arc<Options>,
+    mut watch: Watch,
+) -> Result<ExitCode> {
+    let site = FourChan;
+    let catalog_link = site
+        .catalog_url(board)
+        .with_context(|| format!("{} has no catalog", site.name()))?;
+    info!("Watching the catalog of /{}/", board);
+
+    let mut last_modified = None;
+    let mut watched = HashSet::new();
+    let mut tasks = Vec::new();
+    loop {
+        let load_start = Instant::now();
+        let retry = options.retry.clone();
+        let content = retry.run(|| async {
+            options.limiter.acquire(&catalog_link).await;
+            get_page_content_since(&catalog_link, client, last_modified.as_deref()).await
+        });
+        let mut new_threads = false;
+        match content.await {
+            Ok(PageContent::Modified {
+                content,
+                last_modified: modified,
+            }) => match site.get_catalog(&content) {
+                Ok(ops) => {
+                    for op in ops.iter().filter(|op| filter.matches(op)) {
+                        let id = match u32::try_from(op.no) {
+                            Ok(id) if watched.insert(id) => id,
+                            _ => continue,
+                        };
+                        new_threads = true;
+                        let thread = Thread { board: board.to_owned(), id };
+                        let thread_link = site.page_url(&thread);
+                        println!(
+                            "Watching {} {}",
+                            thread_link,
+                            op.subject.as_deref().unwrap_or_default()
+                        );
+                        let (output, client, options, watch) = (
+                            output.to_owned(),
+                            client.clone(),
+                            Arc::clone(&options),
+                            watch.clone(),
+                        );
+                        tasks.push(tokio::spawn(async move {
+                            let result = watch_thread(&thread_link, &output, &client, &options, watch);
+                            (thread_link.clone(), result.await)
+                        }));
+                    }
+                    last_modified = modified;
+                },
+                Err(err) => error!("Failed to parse the content of {}: {}", catalog_link, err),
+            },
+            Ok(PageContent::NotModified) => {
+                info!("{} has not been modified. Skipped", catalog_link);
+            },
+            Err(err) => {
+                error!("Failed to get content from {}: {}", catalog_link, err);
+                eprintln!("Error: {}", err);
+            },
+        }
+        if Instant::now() > watch.deadline {
+            info!("Runtime exceeded, exiting.");
+            break;
+        }
+        let wait_time = watch.schedule.next(new_threads);
+        if let Some(remaining) = wait_time.checked_sub(load_start.elapsed()) {
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    for task in tasks {
+        match task.await {
+            Ok((thread_link, Err(err))) => eprintln!("Error: failed to watch {}: {}", thread_link, err),
+            Ok(_) => {},
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
 /// Initialize logging for this crate
@@ -128,86 +790,197 @@ fn initialize_logging(verbosity: u8) {
     });
 }
 
-fn mark_as_downloaded(file: &str) -> Result<&str, &str> {
-    let mut db = DOWNLOADED_FILES
-        .lock()
-        .map_err(|_| "Failed to acquire MutexGuard")?;
-    db.push(file.to_string());
+/// Options used for every pass over the thread
+struct Options {
+    concurrent:    usize,
+    /// Template of the names of the files
+    template:      FilenameTemplate,
+    /// Template of the directories of the new threads
+    dir_template:  DirectoryTemplate,
+    /// Directories of the threads under the output directory, by url
+    threads:       Option<Arc<Mutex<ThreadIndex>>>,
+    /// Files to download
+    filter:        MediaFilter,
+    /// Posts whose files are downloaded
+    post_filter:   PostFilter,
+    /// Retries of all the requests, sharing one budget for the whole run
+    retry:         RetryPolicy,
+    /// Limits the API and page requests
+    limiter:       RateLimiter,
+    /// Limits the file downloads
+    media_limiter: RateLimiter,
+    /// How files already downloaded to another thread are added
+    dedup:         LinkMode,
+    /// Files downloaded to all the output directories, by MD5
+    index:         Option<Arc<Mutex<DedupIndex>>>,
+    /// Limits the concurrent downloads of all the threads
+    permits:       Semaphore,
+    /// Paths of the downloads in progress of all the threads
+    reserved:      Arc<Mutex<HashSet<PathBuf>>>,
+    /// Progress bars of all the threads
+    progress:      MultiProgress,
+}
 
-    Ok(file)
+impl Options {
+    /// Returns the options of the entry, without index
+    fn new(entry: &Entry, limiters: Limiters, progress: MultiProgress) -> Self {
+        let concurrent = entry.concurrent.unwrap_or(2);
+        Self {
+            concurrent,
+            template: match (&entry.filename_template, entry.preserve_filenames) {
+                (Some(template), _) => template.clone(),
+                (None, Some(true)) => FilenameTemplate::original(),
+                (None, _) => FilenameTemplate::server(),
+            },
+            dir_template: entry.dir_template.clone().unwrap_or_default(),
+            threads: None,
+            filter: entry.media_filter(),
+            post_filter: entry.post_filter(),
+            retry: RetryPolicy::new(
+                entry.retries.unwrap_or(3),
+                Duration::from_secs(1),
+                Duration::from_secs(60),
+                entry.retry_budget.unwrap_or(100),
+            ),
+            limiter: limiters.pages,
+            media_limiter: limiters.media,
+            dedup: entry.dedup.unwrap_or(LinkMode::Off),
+            index: None,
+            permits: Semaphore::new(concurrent),
+            reserved: Arc::default(),
+            progress,
+        }
+    }
 }
 
-#[tokio::main]
-async fn explore_thread(thread_link: &str, directory: &Path, concurrent: usize) -> Result<(), Error> {
+/// State of the thread kept between reloads
+struct ThreadState {
+    /// Files downloaded to the directory of the thread, on this run or before
+    downloads:     Mutex<DownloadState>,
+    /// `Last-Modified` of the thread when all its files were downloaded
+    last_modified: Option<String>,
+    /// Links that failed to download during the last pass
+    failed:        Vec<Link>,
+    /// Set once the thread won't get new posts
+    end:           Option<ThreadEnd>,
+    /// Number of the last post seen
+    last_post:     Option<u64>,
+    /// Set if the last pass found new posts
+    new_posts:     bool,
+    /// OP of the thread, as of the last pass
+    op:            Option<Post>,
+    /// Set once the thread reached the bump limit
+    bump_limit:    bool,
+    /// Url of the thread continuing this one, once found
+    next:          Option<String>,
+}
+
+impl ThreadState {
+    fn new(downloads: DownloadState) -> Self {
+        Self {
+            downloads:     Mutex::new(downloads),
+            last_modified: None,
+            failed:        Vec::new(),
+            end:           None,
+            last_post:     None,
+            new_posts:     false,
+            op:            None,
+            bump_limit:    false,
+            next:          None,
+        }
+    }
+}
+
+/// Reason a thread won't get new posts
+#[derive(Debug, Clone, Copy)]
+enum ThreadEnd {
+    NotFound,
+    Archived,
+    Closed,
+}
+
+impl fmt::Display for ThreadEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "404'd"),
+            Self::Archived => write!(f, "has been archived"),
+            Self::Closed => write!(f, "has been closed"),
+        }
+    }
+}
+
+async fn explore_thread(
+    thread_link: &str,
+    directory: &Path,
+    client: &Client,
+    options: &Options,
+    state: &mut ThreadState,
+) -> Result<(), Error> {
     let start = Instant::now();
-    let client = Client::builder().user_agent("reqwest").build()?;
+    let registry = Registry::default();
+    let (board, thread) = registry.resolve(thread_link)?;
+    let page_link = board.api_url(&thread);
+    let retry = options.retry.clone();
+    let limiter = &options.limiter;
 
-    match get_page_content(thread_link, &client).await {
-        Ok(page_string) => {
-            info!("Loaded content from {}", thread_link);
-
-            let links_vec = get_image_links(page_string.as_str());
-            let pb = ProgressBar::new(links_vec.len() as u64);
-
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template(
-                        "{spinner:.green.bold} [{elapsed_precise}] [{bar:40.cyan.bold/blue}] \
-                         {pos}/{len} {msg} ({eta})",
-                    )
-                    .context("failed to build progress bar")?
-                    .progress_chars("#>-"),
-            );
-            pb.tick();
-
-            let fetches = futures::stream::iter(links_vec.into_iter().map(|link| {
-                let client = &client;
-                let pb = &pb;
-                async move {
-                    let img_path = directory.join(link.name);
-                    let image_path = img_path.to_str().unwrap();
-                    let has_been_downloaded = async {
-                        let db = DOWNLOADED_FILES
-                            .lock()
-                            .map_err(|_| String::from("Failed to acquire MutexGuard"))
-                            .unwrap();
-                        db.contains(&String::from(image_path))
-                    }
-                    .await;
-
-                    if has_been_downloaded {
-                        info!("Image {} previously downloaded. Skipped", img_path.display());
-                    } else if !img_path.exists() {
-                        match save_image(format!("https:{}", link.url).as_str(), image_path, client).await
-                        {
-                            Ok(path) => {
-                                info!("Saved image to {}", &path);
-                                let result = mark_as_downloaded(&path).unwrap();
-                                info!("{} added to downloaded files", result);
-                            },
-                            Err(err) => {
-                                error!("Couldn't save image {}", image_path);
-                                eprintln!("Error: {}", err);
-                            },
-                        }
-                    } else {
-                        info!("Image {} already exists. Skipped", img_path.display());
-                        let result = mark_as_downloaded(image_path).unwrap();
-                        info!("{} added to downloaded files", result);
-                    }
-                    pb.inc(1);
-                }
-            }))
-            .buffer_unordered(concurrent)
-            .collect::<Vec<()>>();
-            fetches.await;
+    state.new_posts = false;
+    let page_content = retry.run(|| async {
+        limiter.acquire(&page_link).await;
+        get_page_content_since(&page_link, client, state.last_modified.as_deref()).await
+    });
+    match page_content.await {
+        Ok(PageContent::NotModified) => {
+            info!("{} has not been modified. Skipped", page_link);
+        },
+        Ok(PageContent::Modified {
+            content: page_string,
+            last_modified,
+        }) => {
+            info!("Loaded content from {}", page_link);
 
-            pb.finish_with_message("Done");
+            let posts = board
+                .get_posts(&thread, page_string.as_str())
+                .with_context(|| format!("failed to parse the content of {}", page_link))?;
+            let last_post = posts.last().map(|post| post.no);
+            state.new_posts = last_post > state.last_post;
+            state.last_post = last_post;
+            if let Some(op) = posts.first() {
+                state.op = Some(op.clone());
+                state.bump_limit = op.bump_limit;
+                if op.archived {
+                    state.end = Some(ThreadEnd::Archived);
+                } else if op.closed {
+                    state.end = Some(ThreadEnd::Closed);
+                }
+            }
+            let mut links_vec = board.get_post_links(&thread, &posts, &options.template);
+            filter_links(&mut links_vec, &posts, options);
+            let taken = taken_names(directory, &lock(&state.downloads));
+            rename_taken_links(&mut links_vec, &taken);
+            let saved = SavedThread {
+                url: board.page_url(&thread),
+                posts,
+            };
+            if let Err(err) = saved.save(directory, &thread_key(board.name(), &thread)) {
+                error!("Failed to save a copy of {}: {}", page_link, err);
+            }
+            state.failed =
+                download_links(links_vec, directory, client, options, &state.downloads).await?;
+            // Failed files are retried by the next reload, even if the thread
+            // doesn't change
+            state.last_modified = if state.failed.is_empty() {
+                last_modified
+            } else {
+                None
+            };
             info!("Done in {:?}", start.elapsed());
         },
+        Err(DownloadError::NotFound(_)) => {
+            info!("{} was not found", page_link);
+            state.end = Some(ThreadEnd::NotFound);
+        },
         Err(e) => {
-            error!("Failed to get content from {}", thread_link);
-            eprintln!("Error: {}", e);
+            error!("Failed to get content from {}", page_link);
             return Err(anyhow!(e));
         },
     }
@@ -215,16 +988,409 @@ async fn explore_thread(thread_link: &str, directory: &Path, concurrent: usize)
     Ok(())
 }
 
-fn create_directory(thread_link: &str, output: &str) -> Result<PathBuf> {
+/// Downloads the links to the directory, returning the links that failed.
+///
+/// Files recorded in the download state are skipped, even if they have been
+/// deleted since. A file of the directory with the name of a link but not
+/// its MD5 belongs to another url, so the link is saved under a new name.
+async fn download_links(
+    links_vec: Vec<Link>,
+    directory: &Path,
+    client: &Client,
+    options: &Options,
+    downloads: &Mutex<DownloadState>,
+) -> Result<Vec<Link>> {
+    let retry = options.retry.clone();
+    let limiter = &options.media_limiter;
+    let pb = options.progress.add(ProgressBar::new(links_vec.len() as u64));
+    pb.set_prefix(thread_label(directory));
+
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green.bold} {prefix} [{elapsed_precise}] [{bar:40.cyan.bold/blue}] \
+                 {pos}/{len} {msg} ({eta})",
+            )
+            .context("failed to build progress bar")?
+            .progress_chars("#>-"),
+    );
+    pb.tick();
+
+    let linked = AtomicU64::new(0);
+    let saved_bytes = AtomicU64::new(0);
+    let fetches = futures::stream::iter(links_vec.into_iter().map(|mut link| {
+        let retry = &retry;
+        let pb = &pb;
+        let (linked, saved_bytes) = (&linked, &saved_bytes);
+        async move {
+            let img_path = directory.join(&link.name);
+            let has_been_downloaded = lock(downloads).contains(&link.url);
+            let expected_md5 = link
+                .file
+                .as_ref()
+                .map(|file| file.md5.clone())
+                .filter(|md5| !md5.is_empty());
+
+            let mut failure = None;
+            let existing_md5 = if has_been_downloaded || !img_path.exists() {
+                None
+            } else {
+                file_md5(img_path.to_str().unwrap()).await.ok()
+            };
+            if has_been_downloaded {
+                info!("Image {} previously downloaded. Skipped", img_path.display());
+            } else if let Some(md5) =
+                existing_md5.filter(|md5| !matches!(&expected_md5, Some(e) if e != md5))
+            {
+                info!("Image {} already exists. Skipped", img_path.display());
+                let size = img_path.metadata().map_or(0, |metadata| metadata.len());
+                index_download(options, &md5, &img_path, size);
+                record_download(downloads, &link, size, md5);
+            } else {
+                // Any other file with the name belongs to another url
+                let name = reserve_name(directory, &link, &mut lock(&options.reserved));
+                if name != link.name {
+                    warn!(
+                        "{} is another file, saving {} as {}",
+                        img_path.display(),
+                        link.url,
+                        name
+                    );
+                    link.name = name;
+                }
+                let img_path = directory.join(&link.name);
+                let image_path = img_path.to_str().unwrap();
+                if let Some((md5, size)) = link_duplicate(options, &link, &img_path) {
+                    linked.fetch_add(1, Ordering::Relaxed);
+                    saved_bytes.fetch_add(size, Ordering::Relaxed);
+                    record_download(downloads, &link, size, md5);
+                } else {
+                    let url = format!("https:{}", link.url);
+                    let saved = retry.run(|| async {
+                        let _permit = options.permits.acquire().await;
+                        limiter.acquire(&url).await;
+                        download_file(&url, image_path, client, expected_md5.as_deref()).await
+                    });
+                    match saved.await {
+                        Ok(download) => {
+                            info!("Saved image to {}", &download.path);
+                            index_download(options, &download.md5, &img_path, download.size);
+                            record_download(downloads, &link, download.size, download.md5);
+                        },
+                        Err(err) => {
+                            error!("Couldn't save image {}: {}", image_path, err);
+                            failure = Some((link, err));
+                        },
+                    }
+                }
+                lock(&options.reserved).remove(&img_path);
+            }
+            pb.inc(1);
+            failure
+        }
+    }))
+    .buffer_unordered(options.concurrent)
+    .filter_map(futures::future::ready)
+    .collect::<Vec<(Link, DownloadError)>>();
+    let failures = fetches.await;
+
+    let linked = linked.into_inner();
+    if linked > 0 {
+        println!(
+            "Linked {} files already downloaded, saving {}",
+            linked,
+            HumanBytes(saved_bytes.into_inner())
+        );
+    }
+
+    if failures.is_empty() {
+        pb.finish_with_message("Done");
+    } else {
+        pb.finish_with_message(format!("Done, {} failed", failures.len()));
+        eprintln!("Failed to download {} files:", failures.len());
+        for (link, err) in &failures {
+            eprintln!("  https:{}: {}", link.url, err);
+        }
+    }
+    Ok(failures.into_iter().map(|(link, _)| link).collect())
+}
+
+/// Files downloaded again by `verify`
+#[derive(Debug, Clone, Copy)]
+struct Repair {
+    /// Missing and corrupt files
+    problems: bool,
+    /// Files deleted from the directory after being downloaded
+    deleted:  bool,
+}
+
+/// Checks the files of the threads saved to a directory, or of the thread at
+/// `url`, and downloads the bad ones again as asked by `repair`
+async fn verify(
+    directory: &Path,
+    url: Option<&str>,
+    repair: Repair,
+    offline: bool,
+    client: &Client,
+    options: &Options,
+) -> Result<ExitCode> {
+    let threads = match url {
+        Some(url) => vec![url.to_owned()],
+        None => {
+            let copies = SavedThread::load_all(directory).with_context(|| {
+                format!(
+                    "failed to load the copies of the threads in {}",
+                    directory.display()
+                )
+            })?;
+            if copies.is_empty() {
+                return Err(anyhow!(
+                    "--url is required to verify {}, as it has no copy of the thread to tell its site",
+                    directory.display()
+                ));
+            }
+            copies.into_iter().map(|saved| saved.url).collect()
+        },
+    };
+
+    let mut ok = true;
+    // A file of the directory is only unexpected if no thread has it
+    let mut unexpected: Option<HashSet<String>> = None;
+    for thread_link in threads {
+        let (verified, names) =
+            verify_thread(directory, &thread_link, repair, offline, client, options).await?;
+        ok &= verified;
+        let names = names.into_iter().collect();
+        unexpected = Some(match unexpected {
+            Some(unexpected) => &unexpected & &names,
+            None => names,
+        });
+    }
+    let mut unexpected = unexpected.unwrap_or_default().into_iter().collect::<Vec<_>>();
+    unexpected.sort();
+    for name in &unexpected {
+        println!("  {}: unexpected", name);
+    }
+    Ok(if ok && unexpected.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Checks the files of a thread, returning whether none is missing or
+/// corrupt, after repairing them, and the files of the directory that don't
+/// belong to the thread
+async fn verify_thread(
+    directory: &Path,
+    thread_link: &str,
+    repair: Repair,
+    offline: bool,
+    client: &Client,
+    options: &Options,
+) -> Result<(bool, Vec<String>)> {
+    let mut downloads = open_state(thread_link, directory)?;
+    let registry = Registry::default();
+    let (board, thread) = registry.resolve(thread_link)?;
+    let saved = SavedThread::load(directory, &thread_key(board.name(), &thread))
+        .with_context(|| format!("failed to load the copy of the thread in {}", directory.display()))?;
+
+    let posts = if offline {
+        saved
+            .with_context(|| format!("no copy of the thread in {}", directory.display()))?
+            .posts
+    } else {
+        let page_link = board.api_url(&thread);
+        let retry = options.retry.clone();
+        let content = retry.run(|| async {
+            options.limiter.acquire(&page_link).await;
+            get_page_content(&page_link, client).await
+        });
+        match (content.await, saved) {
+            (Ok(content), _) => board
+                .get_posts(&thread, &content)
+                .with_context(|| format!("failed to parse the content of {}", page_link))?,
+            (Err(err), Some(saved)) => {
+                warn!("Failed to get {}: {}, using the saved copy", page_link, err);
+                saved.posts
+            },
+            (Err(err), None) => return Err(anyhow!(err)),
+        }
+    };
+    let mut links = board.get_post_links(&thread, &posts, &options.template);
+    filter_links(&mut links, &posts, options);
+    rename_taken_links(&mut links, &taken_names(directory, &downloads));
+    let report = verify_directory(directory, &links, &downloads).await?;
+
+    println!(
+        "Checked {} files of {} in {}",
+        report.checked,
+        thread_link,
+        directory.display()
+    );
+    for (link, problem) in &report.problems {
+        println!("  {}: {}", link.name, problem);
+    }
+    for link in &report.deleted {
+        println!("  {}: deleted", link.name);
+    }
+    if !report.deleted.is_empty() && !repair.deleted {
+        println!(
+            "{} files were deleted after being downloaded, --restore-deleted downloads them again",
+            report.deleted.len()
+        );
+    }
+    if !report.problems.is_empty() && !repair.problems {
+        println!("{} files are missing or corrupt", report.problems.len());
+    }
+
+    let mut links = Vec::new();
+    if repair.problems {
+        // Corrupt files would otherwise be kept, when the API gives no MD5
+        for (link, problem) in &report.problems {
+            if *problem != Problem::Missing {
+                std::fs::remove_file(directory.join(&link.name))?;
+            }
+        }
+        links.extend(report.problems.iter().map(|(link, _)| link.clone()));
+    }
+    if repair.deleted {
+        links.extend(report.deleted.iter().cloned());
+    }
+    if links.is_empty() {
+        return Ok((report.problems.is_empty(), report.unexpected));
+    }
+    downloads.forget(links.iter().map(|link| link.url.as_str()))?;
+    let downloads = Mutex::new(downloads);
+    let failed = download_links(links, directory, client, options, &downloads).await?;
+    if failed.is_empty() {
+        println!("Repaired {}", thread_link);
+    }
+    let repaired = repair.problems || report.problems.is_empty();
+    Ok((repaired && failed.is_empty(), report.unexpected))
+}
+
+/// Removes the links to the files excluded by the media filter
+fn filter_links(links: &mut Vec<Link>, posts: &[Post], options: &Options) {
+    let found = links.len();
+    links.retain(|link| options.filter.matches_link(link));
+    if let (false, Some(op)) = (options.post_filter.is_empty(), posts.first()) {
+        let kept = posts
+            .iter()
+            .filter(|post| options.post_filter.matches(post, op))
+            .map(|post| post.no)
+            .collect::<HashSet<_>>();
+        links.retain(|link| matches!(link.post, Some(no) if kept.contains(&no)));
+    }
+    if links.len() < found {
+        info!("Skipped {} files excluded by the filters", found - links.len());
+    }
+}
+
+/// Returns the board and the number of the thread downloaded to the directory
+fn thread_label(directory: &Path) -> String {
+    let mut names = directory
+        .iter()
+        .rev()
+        .take(2)
+        .map(|name| name.to_string_lossy())
+        .collect::<Vec<_>>();
+    names.reverse();
+    names.join("/")
+}
+
+/// Adds the file of the link to `path` from another thread with the same
+/// MD5, returning its MD5 and size
+fn link_duplicate(options: &Options, link: &Link, path: &Path) -> Option<(String, u64)> {
+    let index = options.index.as_ref()?;
+    let file = link.file.as_ref().filter(|file| !file.md5.is_empty())?;
+    let size = Some(file.fsize).filter(|size| *size > 0);
+    let source = lock(index).find(&file.md5, size)?;
+    match options.dedup.link(&source, path) {
+        Ok(()) => {
+            info!(
+                "Linked {} to {} ({})",
+                path.display(),
+                source.display(),
+                options.dedup
+            );
+            let size = path.metadata().map_or(0, |metadata| metadata.len());
+            Some((file.md5.clone(), size))
+        },
+        Err(err) => {
+            warn!(
+                "Failed to link {} to {}: {}",
+                path.display(),
+                source.display(),
+                err
+            );
+            None
+        },
+    }
+}
+
+/// Adds a downloaded file to the index of the downloaded files
+fn index_download(options: &Options, md5: &str, path: &Path, size: u64) {
+    if let Some(index) = &options.index {
+        if let Err(err) = lock(index).insert(md5, path, size) {
+            error!("Failed to index {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Adds a verified file to the download state
+fn record_download(downloads: &Mutex<DownloadState>, link: &Link, size: u64, md5: String) {
+    let record = Record::new(link, &link.name, size, md5);
+    match lock(downloads).insert(record) {
+        Ok(()) => info!("{} added to downloaded files", link.name),
+        Err(err) => error!("Failed to record the download of {}: {}", link.name, err),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns the directory of the thread, creating it if needed.
+///
+/// A thread keeps the directory recorded in the index of the output
+/// directory, or the `board/thread` directory it was saved to before the
+/// index. A new thread gets the directory rendered from the template, which
+/// may need its OP to be loaded first.
+async fn create_directory(
+    thread_link: &str,
+    output: &str,
+    client: &Client,
+    options: &Options,
+) -> Result<PathBuf> {
     let workpath = env::current_dir()?;
     info!("Working from {}", workpath.display());
 
-    let thread = get_thread_info(thread_link);
-
-    let directory = workpath
-        .join(output)
-        .join(thread.board)
-        .join(format!("{}", thread.id));
+    let registry = Registry::default();
+    let (board, thread) = registry.resolve(thread_link)?;
+    let url = board.page_url(&thread);
+    let root = workpath.join(output);
+    let known = options
+        .threads
+        .as_ref()
+        .and_then(|threads| lock(threads).get(&url));
+    let legacy = root.join(&thread.board).join(thread.id.to_string());
+    let directory = match known {
+        Some(directory) => directory,
+        None if legacy.is_dir() => legacy,
+        None => {
+            let op = if options.dir_template.needs_op() {
+                fetch_op(board, &thread, client, options).await
+            } else {
+                None
+            };
+            root.join(options.dir_template.render(&ThreadContext {
+                site:   board.name(),
+                thread: &thread,
+                op:     op.as_ref(),
+            }))
+        },
+    };
     if !directory.exists() {
         match create_dir_all(&directory) {
             Ok(_) => {
@@ -237,11 +1403,46 @@ fn create_directory(thread_link: &str, output: &str) -> Result<PathBuf> {
             },
         }
     }
+    if let Some(threads) = &options.threads {
+        if let Err(err) = lock(threads).insert(&url, &directory) {
+            error!("Failed to index {}: {}", directory.display(), err);
+        }
+    }
 
-    info!("Downloaded: {} in {}", thread_link, output);
+    info!("Downloaded: {} in {}", thread_link, directory.display());
     Ok(directory)
 }
 
+/// Returns the OP of the thread, if it can be loaded
+async fn fetch_op(
+    board: &dyn Imageboard,
+    thread: &Thread,
+    client: &Client,
+    options: &Options,
+) -> Option<Post> {
+    let page_link = board.api_url(thread);
+    let retry = options.retry.clone();
+    let content = retry.run(|| async {
+        options.limiter.acquire(&page_link).await;
+        get_page_content(&page_link, client).await
+    });
+    match content.await.map(|content| board.get_posts(thread, &content)) {
+        Ok(Ok(posts)) => posts.into_iter().next(),
+        Ok(Err(err)) => {
+            warn!("Failed to parse the content of {}: {}", page_link, err);
+            None
+        },
+        Err(err) => {
+            warn!("Failed to get content from {}: {}", page_link, err);
+            None
+        },
+    }
+}
+
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|err| err.to_string())
+}
+
 /// Build the command-line application
 fn build_app() -> Command<'static> {
     log::debug!("Building application");
@@ -257,17 +1458,60 @@ fn build_app() -> Command<'static> {
             ColorChoice::Never
         })
         .setting(AppSettings::DeriveDisplayOrder)
+        .subcommand_negates_reqs(true)
         .infer_long_args(true)
         .dont_collapse_args_in_usage(true)
         .arg(
             Arg::new("thread")
                 .short('t')
                 .long("thread")
-                .required(true)
+                .required_unless_present_any(["board", "input_file"])
                 .takes_value(true)
+                .multiple_occurrences(true)
                 .value_name("URL")
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
-                .help("URL of the thread"),
+                .help("URL of the thread, can be given several times"),
+        )
+        .arg(
+            Arg::new("input_file")
+                .short('I')
+                .long("input-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .conflicts_with("board")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("File listing the URLs of the threads, one per line, or - for the standard input"),
+        )
+        .arg(
+            Arg::new("board")
+                .short('b')
+                .long("board")
+                .takes_value(true)
+                .value_name("BOARD")
+                .conflicts_with("thread")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("Watch the catalog of a 4chan board, and download every thread it selects"),
+        )
+        .arg(
+            Arg::new("subject")
+                .long("subject")
+                .takes_value(true)
+                .value_name("REGEX")
+                .requires("board")
+                .conflicts_with("thread")
+                .value_parser(parse_regex)
+                .help("Select the threads of the board whose subject matches"),
+        )
+        .arg(
+            Arg::new("comment")
+                .long("comment")
+                .takes_value(true)
+                .value_name("REGEX")
+                .requires("board")
+                .conflicts_with("thread")
+                .value_parser(parse_regex)
+                .help("Select the threads of the board whose OP comment matches"),
         )
         .arg(
             Arg::new("output")
@@ -278,13 +1522,38 @@ fn build_app() -> Command<'static> {
                 .value_hint(ValueHint::DirPath)
                 .help("Output directory (Default is 'downloads')"),
         )
-        // .arg(
-        //     Arg::new("preserve_filenames")
-        //         .short('p')
-        //         .long("preserve-filenames")
-        //         .takes_value(false)
-        //         .help("Preserve the filenames that are found on 4chan/4plebs"),
-        // )
+        .arg(
+            Arg::new("dir_template")
+                .long("dir-template")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .value_parser(value_parser!(DirectoryTemplate))
+                .help(
+                    "Save the new threads to a directory named from a template, such as \
+                     '{site}/{board}/{thread}-{subject_slug}' (Default is '{board}/{thread}')",
+                ),
+        )
+        .arg(
+            Arg::new("preserve_filenames")
+                .short('p')
+                .long("preserve-filenames")
+                .takes_value(false)
+                .global(true)
+                .help("Preserve the filenames that are found on 4chan/4plebs"),
+        )
+        .arg(
+            Arg::new("filename_template")
+                .long("filename-template")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .value_parser(value_parser!(FilenameTemplate))
+                .conflicts_with("preserve_filenames")
+                .global(true)
+                .help(
+                    "Name the files from a template, such as '{no}_{original}.{ext}' (Default is \
+                     '{tim}.{ext}')",
+                ),
+        )
         .arg(
             Arg::new("reload")
                 .short('r')
@@ -292,14 +1561,48 @@ fn build_app() -> Command<'static> {
                 .takes_value(false)
                 .help("Reload thread every t minutes to get new images"),
         )
+        .arg(
+            Arg::new("follow")
+                .short('f')
+                .long("follow")
+                .takes_value(false)
+                .requires("reload")
+                .help("Follow the next thread of a general when this one ends"),
+        )
         .arg(
             Arg::new("interval")
                 .short('i')
                 .long("interval")
                 .takes_value(true)
                 .value_name("INTERVAL")
-                .value_parser(value_parser!(u64))
-                .help("Time between each reload (in minutes. Default is 5)"),
+                .value_parser(parse_duration)
+                .help("Time between each reload (in minutes, or with a unit such as 30s. Default is 5)"),
+        )
+        .arg(
+            Arg::new("adaptive")
+                .short('a')
+                .long("adaptive")
+                .takes_value(false)
+                .conflicts_with("interval")
+                .help("Reload quickly while the thread is active, and slow down when it is not"),
+        )
+        .arg(
+            Arg::new("min_interval")
+                .long("min-interval")
+                .takes_value(true)
+                .value_name("INTERVAL")
+                .value_parser(parse_duration)
+                .requires("adaptive")
+                .help("Shortest time between each adaptive reload (Default is 30s)"),
+        )
+        .arg(
+            Arg::new("max_interval")
+                .long("max-interval")
+                .takes_value(true)
+                .value_name("INTERVAL")
+                .value_parser(parse_duration)
+                .requires("adaptive")
+                .help("Longest time between each adaptive reload (Default is 10m)"),
         )
         .arg(
             Arg::new("limit")
@@ -307,8 +1610,8 @@ fn build_app() -> Command<'static> {
                 .long("limit")
                 .takes_value(true)
                 .value_name("LIMIT")
-                .value_parser(value_parser!(u64))
-                .help("Time limit for execution (in minutes. Default is 120)"),
+                .value_parser(parse_duration)
+                .help("Time limit for execution (in minutes, or with a unit such as 1h. Default is 120)"),
         )
         .arg(
             Arg::new("concurrent")
@@ -316,9 +1619,171 @@ fn build_app() -> Command<'static> {
                 .long("concurrent")
                 .takes_value(true)
                 .value_name("NUM-REQUESTS")
-                .value_parser(value_parser!(usize))
+                .value_parser(value_parser!(u64).range(1..))
+                .global(true)
                 .help("Number of concurrent requests (Default is 2)"),
         )
+        .arg(
+            Arg::new("retries")
+                .long("retries")
+                .takes_value(true)
+                .value_name("NUM-RETRIES")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Number of retries of a failed request (Default is 3)"),
+        )
+        .arg(
+            Arg::new("retry_budget")
+                .long("retry-budget")
+                .takes_value(true)
+                .value_name("NUM-RETRIES")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Number of retries allowed for the whole run (Default is 100)"),
+        )
+        .arg(
+            Arg::new("rate_limit")
+                .long("rate-limit")
+                .takes_value(true)
+                .value_name("REQUESTS")
+                .value_parser(parse_rate)
+                .global(true)
+                .help(
+                    "Maximum API and page requests per second to each host, 0 to disable (Default is 1)",
+                ),
+        )
+        .arg(
+            Arg::new("media_rate_limit")
+                .long("media-rate-limit")
+                .takes_value(true)
+                .value_name("REQUESTS")
+                .value_parser(parse_rate)
+                .global(true)
+                .help("Maximum file downloads per second to each host, 0 to disable (Default is 10)"),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .takes_value(true)
+                .value_name("MODE")
+                .value_parser(value_parser!(LinkMode))
+                .help(
+                    "Link files already downloaded to another thread instead of downloading them again: \
+                     hardlink, symlink, reflink or off (Default is off)",
+                ),
+        )
+        .arg(
+            Arg::new("ext")
+                .long("ext")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .value_name("EXTENSIONS")
+                .global(true)
+                .help("Only download the files with these extensions, such as jpg,png"),
+        )
+        .arg(
+            Arg::new("exclude_ext")
+                .long("exclude-ext")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .value_name("EXTENSIONS")
+                .global(true)
+                .help("Don't download the files with these extensions, such as gif,webm"),
+        )
+        .arg(
+            Arg::new("min_size")
+                .long("min-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .value_parser(parse_size)
+                .global(true)
+                .help("Only download the files of at least this size, such as 200k"),
+        )
+        .arg(
+            Arg::new("max_size")
+                .long("max-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .value_parser(parse_size)
+                .global(true)
+                .help("Only download the files of at most this size, such as 8MB"),
+        )
+        .arg(
+            Arg::new("min_width")
+                .long("min-width")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Only download the images and videos at least this wide"),
+        )
+        .arg(
+            Arg::new("max_width")
+                .long("max-width")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Only download the images and videos at most this wide"),
+        )
+        .arg(
+            Arg::new("min_height")
+                .long("min-height")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Only download the images and videos at least this high"),
+        )
+        .arg(
+            Arg::new("max_height")
+                .long("max-height")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .value_parser(value_parser!(u32))
+                .global(true)
+                .help("Only download the images and videos at most this high"),
+        )
+        .arg(
+            Arg::new("aspect_ratio")
+                .long("aspect-ratio")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("RATIO")
+                .value_parser(value_parser!(AspectRatio))
+                .global(true)
+                .help(
+                    "Only download the images and videos with an aspect ratio such as landscape, \
+                     portrait, square, 16:9 or 16:10-21:9, can be given several times",
+                ),
+        )
+        .arg(
+            Arg::new("include_posts")
+                .long("include-posts")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("RULE")
+                .value_parser(value_parser!(PostRule))
+                .global(true)
+                .help(
+                    "Only download the files of the posts matching a rule such as op, comment:REGEX, \
+                     id:ID, trip:TRIPCODE or name:NAME, combined with && and can be given several times",
+                ),
+        )
+        .arg(
+            Arg::new("exclude_posts")
+                .long("exclude-posts")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("RULE")
+                .value_parser(value_parser!(PostRule))
+                .global(true)
+                .help(
+                    "Don't download the files of the posts matching a rule, can be given several times",
+                ),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -328,6 +1793,63 @@ fn build_app() -> Command<'static> {
                 .action(ArgAction::Count)
                 .help("Display debugging messages"),
         )
+        .subcommand(
+            Command::new("daemon")
+                .about(
+                    "Watch the threads and boards listed in a config file, reloading it when it changes",
+                )
+                .arg(
+                    Arg::new("config")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("CONFIG")
+                        .value_hint(ValueHint::FilePath)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("TOML file listing the threads and boards to watch"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the files of a thread directory against the thread")
+                .arg(
+                    Arg::new("directory")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("DIRECTORY")
+                        .value_hint(ValueHint::DirPath)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory of the thread, such as downloads/wg/6872254"),
+                )
+                .arg(
+                    Arg::new("url")
+                        .long("url")
+                        .takes_value(true)
+                        .value_name("URL")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help(
+                            "URL of the thread to check, required for a directory without a copy of the \
+                             thread (Default is every thread saved to the directory)",
+                        ),
+                )
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .takes_value(false)
+                        .help("Download the missing and corrupt files again"),
+                )
+                .arg(
+                    Arg::new("restore_deleted")
+                        .long("restore-deleted")
+                        .takes_value(false)
+                        .help("Download the files deleted after being downloaded again"),
+                )
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .takes_value(false)
+                        .help("Check against the copy of the thread saved in the directory"),
+                ),
+        )
 }
 
 #[test]
@@ -0,0 +1,196 @@
+//! Selection of the threads of a board catalog
+
+use crate::Post;
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Selects the threads of a catalog by the subject and the comment of their
+/// OP.
+///
+/// A thread is selected if its subject or its comment matches the matching
+/// expression. The comment is matched as plain text. A filter without any
+/// expression selects every thread.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{catalog::ThreadFilter, post::get_catalog_posts};
+/// use regex::Regex;
+/// let content = r#"[{"page": 1, "threads": [
+///     {"no": 1, "time": 0, "sub": "Wallpaper General"},
+///     {"no": 2, "time": 0, "sub": "Help me find this wallpaper"}]}]"#;
+/// let filter = ThreadFilter::new(Some(Regex::new("(?i)general").unwrap()), None);
+/// let threads: Vec<_> = get_catalog_posts(content)
+///     .unwrap()
+///     .into_iter()
+///     .filter(|op| filter.matches(op))
+///     .collect();
+///
+/// assert_eq!(threads.len(), 1);
+/// assert_eq!(threads[0].no, 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFilter {
+    subject: Option<Regex>,
+    comment: Option<Regex>,
+}
+
+impl ThreadFilter {
+    /// Returns a filter matching the subject and the comment of the OPs
+    #[must_use]
+    pub fn new(subject: Option<Regex>, comment: Option<Regex>) -> Self {
+        Self { subject, comment }
+    }
+
+    /// Returns true if the thread of the OP is selected
+    #[must_use]
+    pub fn matches(&self, op: &Post) -> bool {
+        if self.subject.is_none() && self.comment.is_none() {
+            return true;
+        }
+        let subject = match (&self.subject, op.subject_text()) {
+            (Some(regex), Some(subject)) => regex.is_match(&subject),
+            _ => false,
+        };
+        subject
+            || match (&self.comment, op.comment_text()) {
+                (Some(regex), Some(comment)) => regex.is_match(&comment),
+                _ => false,
+            }
+    }
+}
+
+/// Returns the thread of the catalog continuing a general thread.
+///
+/// The next thread is a newer thread whose OP links back to the previous one,
+/// or else the newer thread whose subject is the closest to the subject of
+/// the previous OP. Subjects are compared by their words, leaving out the
+/// numbers, so `/wg/ - Wallpaper General #42` follows
+/// `/wg/ - Wallpaper General #41`.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{catalog::find_next_thread, post::get_catalog_posts};
+/// let content = r#"[{"page": 1, "threads": [
+///     {"no": 1, "time": 0, "sub": "Wallpaper General #41"},
+///     {"no": 2, "time": 0, "sub": "Wallpaper General #42"}]}]"#;
+/// let ops = get_catalog_posts(content).unwrap();
+///
+/// assert_eq!(find_next_thread(&ops[0], &ops).unwrap().no, 2);
+/// ```
+#[must_use]
+pub fn find_next_thread<'a>(previous: &Post, catalog: &'a [Post]) -> Option<&'a Post> {
+    let newer = || catalog.iter().filter(|op| op.no > previous.no);
+    let back_link = Regex::new(&format!(r"(>>|/thread/|#p){}\b", previous.no)).ok()?;
+    let linking = newer()
+        .filter(|op| {
+            matches!(&op.comment, Some(comment) if back_link.is_match(comment))
+                || matches!(op.comment_text(), Some(text) if back_link.is_match(&text))
+        })
+        .min_by_key(|op| op.no);
+    if linking.is_some() {
+        return linking;
+    }
+
+    let words = subject_words(&previous.subject_text()?);
+    newer()
+        .filter_map(|op| Some((op, similarity(&words, &subject_words(&op.subject_text()?)))))
+        .filter(|(_, score)| *score >= 0.5)
+        .max_by(|(a, a_score), (b, b_score)| {
+            a_score
+                .partial_cmp(b_score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(b.no.cmp(&a.no))
+        })
+        .map(|(op, _)| op)
+}
+
+/// Returns the lowercase words of a subject, without the numbers
+fn subject_words(subject: &str) -> HashSet<String> {
+    subject
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.trim_matches(|c: char| c.is_ascii_digit()).to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Returns the share of the words found in both subjects
+fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::get_catalog_posts;
+
+    #[test]
+    fn it_filters_threads() {
+        let content = r#"[{"page": 1, "threads": [
+            {"no": 1, "sub": "/wg/ - Wallpaper General"},
+            {"no": 2, "com": "Previous: <a class=\"quotelink\">&gt;&gt;1</a><br>Wallpaper general"},
+            {"no": 3, "sub": "Rate my desktop", "com": "no generals here"},
+            {"no": 4},
+            {"no": 5, "sub": "Tom &amp; Jerry"}
+        ]}]"#;
+        let ops = get_catalog_posts(content).unwrap();
+        let selected = |filter: &ThreadFilter| {
+            ops.iter()
+                .filter(|op| filter.matches(op))
+                .map(|op| op.no)
+                .collect::<Vec<_>>()
+        };
+        let regex = |re| Some(Regex::new(re).unwrap());
+
+        assert_eq!(selected(&ThreadFilter::default()), [1, 2, 3, 4, 5]);
+        assert_eq!(selected(&ThreadFilter::new(regex("Wallpaper General"), None)), [
+            1
+        ]);
+        assert_eq!(
+            selected(&ThreadFilter::new(None, regex("(?i)wallpaper general"))),
+            [2]
+        );
+        assert_eq!(
+            selected(&ThreadFilter::new(regex("Wallpaper"), regex("^Previous: >>\\d+"))),
+            [1, 2]
+        );
+        assert_eq!(selected(&ThreadFilter::new(regex("^Tom & Jerry$"), None)), [5]);
+    }
+
+    #[test]
+    fn it_finds_the_next_thread() {
+        let content = r#"[{"page": 1, "threads": [
+            {"no": 100, "sub": "/wg/ - Wallpaper General #41"},
+            {"no": 90, "sub": "/wg/ - Wallpaper General #40"},
+            {"no": 105, "sub": "Minimalist wallpapers"},
+            {"no": 110, "sub": "/wg/ - Wallpaper General #42"},
+            {"no": 120, "sub": "/wg/ - Wallpaper General #42 (real)"},
+            {"no": 125, "com": "Not related to <a href=\"/wg/thread/999#p1000\">&gt;&gt;1000</a>"},
+            {"no": 130, "com": "Previous thread: <a href=\"/wg/thread/100#p100\">&gt;&gt;100</a>"}
+        ]}]"#;
+        let ops = get_catalog_posts(content).unwrap();
+        let next = |no| {
+            let previous = ops.iter().find(|op| op.no == no).unwrap();
+            find_next_thread(previous, &ops).map(|op| op.no)
+        };
+        // A link back to the previous thread wins over the subject
+        assert_eq!(next(100), Some(130));
+        assert_eq!(next(90), Some(100));
+        assert_eq!(next(120), None);
+
+        let subjects = get_catalog_posts(
+            r#"[{"page": 1, "threads": [
+            {"no": 1, "sub": "/g/ - Desktop Thread"},
+            {"no": 2, "sub": "Desktop Thread - Ricing edition"},
+            {"no": 3, "sub": "/g/ - desktop thread"}
+        ]}]"#,
+        )
+        .unwrap();
+        assert_eq!(find_next_thread(&subjects[0], &subjects).unwrap().no, 3);
+    }
+}
@@ -0,0 +1,438 @@
+//! Config file of the daemon mode
+
+use crate::{
+    dedup::LinkMode,
+    filter::{parse_size, AspectRatio, MediaFilter, PostFilter, PostRule},
+    ratelimit::check_rate,
+    schedule::{from_minutes, parse_duration},
+    template::{DirectoryTemplate, FilenameTemplate},
+    Error,
+};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::{fs, path::Path, str::FromStr, time::Duration};
+
+/// Threads and boards watched by the daemon, read from a TOML file.
+///
+/// Every `[[watch]]` table is an entry, watching either threads or the
+/// catalog of a board. Its keys are the long options of the command line.
+/// The keys of the `[defaults]` table apply to the entries that don't set
+/// them.
+///
+/// ```toml
+/// rate-limit = 1
+///
+/// [defaults]
+/// output = "downloads"
+/// interval = "5m"
+/// limit = "24h"
+///
+/// [[watch]]
+/// thread = ["https://boards.4chan.org/wg/thread/6872254"]
+/// follow = true
+///
+/// [[watch]]
+/// board = "wg"
+/// subject = "(?i)wallpaper general"
+/// concurrent = 4
+/// ```
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::config::Config;
+/// let config = Config::parse("[[watch]]\nboard = \"wg\"").unwrap();
+/// assert_eq!(config.entries[0].board.as_deref(), Some("wg"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Maximum API and page requests per second to each host, shared by
+    /// every entry
+    pub rate_limit:       Option<f64>,
+    /// Maximum file downloads per second to each host, shared by every entry
+    pub media_rate_limit: Option<f64>,
+    /// Options of the entries that don't set them
+    pub defaults:         Entry,
+    /// Threads and boards to watch, with the defaults applied
+    #[serde(rename = "watch")]
+    pub entries:          Vec<Entry>,
+}
+
+impl Config {
+    /// Parses the content of a config file, and applies the defaults to the
+    /// entries
+    ///
+    /// # Errors
+    ///
+    /// Fails if the content is not valid TOML, has unknown keys, or has an
+    /// entry that doesn't watch exactly one of threads or a board.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut config: Self = toml::from_str(content)?;
+        for rate in config.rate_limit.iter().chain(&config.media_rate_limit) {
+            check_rate(*rate).map_err(Error::Config)?;
+        }
+        let defaults = &config.defaults;
+        if !defaults.thread.is_empty()
+            || defaults.board.is_some()
+            || defaults.subject.is_some()
+            || defaults.comment.is_some()
+        {
+            return Err(Error::Config(String::from(
+                "[defaults] can't set thread, board, subject or comment",
+            )));
+        }
+        for entry in &mut config.entries {
+            entry.apply_defaults(defaults);
+            entry.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Reads and parses the config file
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be read, or if [`Config::parse`] fails.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+}
+
+/// Threads or board watched by the daemon, with the options of the command
+/// line
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Entry {
+    /// Urls of the threads, given as a string or an array
+    #[serde(deserialize_with = "one_or_many")]
+    pub thread:             Vec<String>,
+    /// Board whose catalog is watched
+    pub board:              Option<String>,
+    /// Regex selecting the threads of the board by subject
+    pub subject:            Option<String>,
+    /// Regex selecting the threads of the board by OP comment
+    pub comment:            Option<String>,
+    pub output:             Option<String>,
+    pub preserve_filenames: Option<bool>,
+    /// Template of the names of the files, used instead of
+    /// `preserve-filenames` when both are set
+    #[serde(deserialize_with = "parsed")]
+    pub filename_template:  Option<FilenameTemplate>,
+    #[serde(deserialize_with = "parsed")]
+    pub dir_template:       Option<DirectoryTemplate>,
+    pub reload:             Option<bool>,
+    pub follow:             Option<bool>,
+    #[serde(deserialize_with = "duration")]
+    pub interval:           Option<Duration>,
+    pub adaptive:           Option<bool>,
+    #[serde(deserialize_with = "duration")]
+    pub min_interval:       Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub max_interval:       Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub limit:              Option<Duration>,
+    pub concurrent:         Option<usize>,
+    pub retries:            Option<u32>,
+    pub retry_budget:       Option<u32>,
+    #[serde(deserialize_with = "parsed")]
+    pub dedup:              Option<LinkMode>,
+    /// Extensions to download, given as a string or an array
+    #[serde(deserialize_with = "list")]
+    pub ext:                Option<Vec<String>>,
+    /// Extensions never downloaded, given as a string or an array
+    #[serde(deserialize_with = "list")]
+    pub exclude_ext:        Option<Vec<String>>,
+    #[serde(deserialize_with = "size")]
+    pub min_size:           Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub max_size:           Option<u64>,
+    pub min_width:          Option<u32>,
+    pub max_width:          Option<u32>,
+    pub min_height:         Option<u32>,
+    pub max_height:         Option<u32>,
+    /// Aspect ratios to download, given as a string or an array
+    #[serde(deserialize_with = "parsed_list")]
+    pub aspect_ratio:       Option<Vec<AspectRatio>>,
+    /// Rules selecting the posts to download, given as a string or an array
+    #[serde(deserialize_with = "parsed_list")]
+    pub include_posts:      Option<Vec<PostRule>>,
+    /// Rules excluding posts, given as a string or an array
+    #[serde(deserialize_with = "parsed_list")]
+    pub exclude_posts:      Option<Vec<PostRule>>,
+}
+
+impl Entry {
+    /// Returns what the entry watches, to tell it apart in messages
+    #[must_use]
+    pub fn label(&self) -> String {
+        match &self.board {
+            Some(board) => format!("/{}/", board),
+            None => self.thread.join(", "),
+        }
+    }
+
+    /// Sets the options that are not set from `defaults`
+    fn apply_defaults(&mut self, defaults: &Self) {
+        macro_rules! apply {
+            ($($option:ident),*) => {
+                $(if self.$option.is_none() {
+                    self.$option = defaults.$option.clone();
+                })*
+            };
+        }
+        apply!(
+            output,
+            preserve_filenames,
+            filename_template,
+            dir_template,
+            reload,
+            follow,
+            interval,
+            adaptive,
+            min_interval,
+            max_interval,
+            limit,
+            concurrent,
+            retries,
+            retry_budget,
+            dedup,
+            ext,
+            exclude_ext,
+            min_size,
+            max_size,
+            min_width,
+            max_width,
+            min_height,
+            max_height,
+            aspect_ratio,
+            include_posts,
+            exclude_posts
+        );
+    }
+
+    /// Returns the filter selecting the files to download
+    #[must_use]
+    pub fn media_filter(&self) -> MediaFilter {
+        MediaFilter {
+            extensions:         self.ext.clone().unwrap_or_default(),
+            exclude_extensions: self.exclude_ext.clone().unwrap_or_default(),
+            min_size:           self.min_size,
+            max_size:           self.max_size,
+            min_width:          self.min_width,
+            max_width:          self.max_width,
+            min_height:         self.min_height,
+            max_height:         self.max_height,
+            aspect_ratios:      self.aspect_ratio.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the filter selecting the posts whose files are downloaded
+    #[must_use]
+    pub fn post_filter(&self) -> PostFilter {
+        PostFilter {
+            include: self.include_posts.clone().unwrap_or_default(),
+            exclude: self.exclude_posts.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Checks the options that depend on each other, as the command line does
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::Config(format!("{}: {}", self.label(), message)));
+        match (self.thread.is_empty(), &self.board) {
+            (true, None) => return invalid(String::from("set either thread or board")),
+            (false, Some(_)) => return invalid(String::from("set only one of thread and board")),
+            (false, None) if self.subject.is_some() || self.comment.is_some() => {
+                return invalid(String::from("subject and comment require a board"));
+            },
+            _ => {},
+        }
+        for regex in self.subject.iter().chain(&self.comment) {
+            if let Err(err) = Regex::new(regex) {
+                return invalid(err.to_string());
+            }
+        }
+        if self.follow == Some(true) && self.reload == Some(false) {
+            return invalid(String::from("follow requires reload"));
+        }
+        if self.concurrent == Some(0) {
+            return invalid(String::from("concurrent must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    one_or_many(deserializer).map(Some)
+}
+
+/// Parses a string or an array of strings as on the command line
+fn parsed_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let values = one_or_many(deserializer)?;
+    values
+        .iter()
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Parses a size given as a number of bytes, or as a string such as `"5MB"`
+/// like on the command line
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Value::deserialize(deserializer)? {
+        Value::Bytes(bytes) => Ok(Some(bytes)),
+        Value::Text(text) => parse_size(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Parses a duration given as a number of minutes, or as a string such as
+/// `"30s"` like on the command line
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Minutes(u64),
+        Text(String),
+    }
+
+    match Value::deserialize(deserializer)? {
+        Value::Minutes(minutes) => from_minutes(minutes).map(Some).map_err(de::Error::custom),
+        Value::Text(text) => parse_duration(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Parses a string as on the command line
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_configs() {
+        let config = Config::parse(
+            r#"
+            rate-limit = 0.5
+            media-rate-limit = 5
+
+            [defaults]
+            output = "downloads"
+            interval = 10
+            concurrent = 2
+
+            [[watch]]
+            thread = "https://boards.4chan.org/wg/thread/6872254"
+            follow = true
+            limit = "24h"
+            include-posts = ["op", "comment:(?i)source"]
+            exclude-posts = "trip:!Ep8pui8Vw2 && comment:OC"
+
+            [[watch]]
+            board = "wg"
+            subject = "(?i)wallpaper general"
+            output = "wallpapers"
+            adaptive = true
+            min-interval = "30s"
+            dedup = "symlink"
+            filename-template = "{no}_{original}.{ext}"
+            dir-template = "{board}/{thread}-{subject_slug}"
+            exclude-ext = "gif"
+            min-width = 1920
+            max-size = "8MB"
+            aspect-ratio = ["landscape", "16:10-21:9"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit, Some(0.5));
+        assert_eq!(config.media_rate_limit, Some(5.0));
+        assert_eq!(config.entries.len(), 2);
+
+        let thread = &config.entries[0];
+        assert_eq!(thread.thread, ["https://boards.4chan.org/wg/thread/6872254"]);
+        assert_eq!(thread.output.as_deref(), Some("downloads"));
+        assert_eq!(thread.interval, Some(Duration::from_secs(600)));
+        assert_eq!(thread.limit, Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(thread.follow, Some(true));
+        assert_eq!(thread.dedup, None);
+
+        let board = &config.entries[1];
+        assert_eq!(board.label(), "/wg/");
+        assert_eq!(board.output.as_deref(), Some("wallpapers"));
+        assert_eq!(board.concurrent, Some(2));
+        assert_eq!(board.min_interval, Some(Duration::from_secs(30)));
+        assert_eq!(board.dedup, Some(LinkMode::Symlink));
+        assert_eq!(
+            board.filename_template.as_ref().map(ToString::to_string),
+            Some(String::from("{no}_{original}.{ext}"))
+        );
+        assert!(board.dir_template.as_ref().unwrap().needs_op());
+        let filter = board.media_filter();
+        assert_eq!(filter.exclude_extensions, ["gif"]);
+        assert_eq!(filter.min_width, Some(1920));
+        assert_eq!(filter.max_size, Some(8 << 20));
+        assert_eq!(filter.aspect_ratios.len(), 2);
+        assert!(thread.media_filter().is_empty());
+        let posts = thread.post_filter();
+        assert_eq!(posts.include.len(), 2);
+        assert_eq!(posts.exclude.len(), 1);
+        assert!(board.post_filter().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_invalid_configs() {
+        for content in [
+            "[[watch]]\noutput = \"downloads\"",
+            "[[watch]]\nboard = \"wg\"\nthread = \"https://boards.4chan.org/wg/thread/1\"",
+            "[[watch]]\nthread = \"https://boards.4chan.org/wg/thread/1\"\nsubject = \"x\"",
+            "[[watch]]\nboard = \"wg\"\nsubject = \"(\"",
+            "[[watch]]\nboard = \"wg\"\ninterval = \"5d\"",
+            "[[watch]]\nboard = \"wg\"\nlimit = 307445734561825861",
+            "[[watch]]\nboard = \"wg\"\ndedup = \"copy\"",
+            "[[watch]]\nboard = \"wg\"\nfilename-template = \"{size}\"",
+            "[[watch]]\nboard = \"wg\"\naspect-ratio = \"wide\"",
+            "[[watch]]\nboard = \"wg\"\nmax-size = \"5TB\"",
+            "[[watch]]\nboard = \"wg\"\ninclude-posts = \"poster:Aa1\"",
+            "[[watch]]\nboard = \"wg\"\nconcurent = 2",
+            "[[watch]]\nboard = \"wg\"\nconcurrent = 0",
+            "[defaults]\nboard = \"wg\"",
+            "rate-limit = -1\n[[watch]]\nboard = \"wg\"",
+            "rate-limit = nan\n[[watch]]\nboard = \"wg\"",
+            "media-rate-limit = -1\n[[watch]]\nboard = \"wg\"",
+            "[[watch]]\nthread = \"https://boards.4chan.org/wg/thread/1\"\nreload = false\nfollow = true",
+        ] {
+            assert!(Config::parse(content).is_err(), "{}", content);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Deduplication of the files shared by several threads
+
+use crate::{
+    journal::{find_directories, Journal},
+    state::DownloadState,
+    Error,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// How a file already downloaded elsewhere is added to a thread directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Hard link to the existing file
+    Hardlink,
+    /// Symbolic link to the existing file
+    Symlink,
+    /// Copy on write clone of the existing file, on the filesystems that
+    /// support it
+    Reflink,
+    /// Always download the file
+    Off,
+}
+
+impl LinkMode {
+    /// Adds the file at `source` to `destination`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the link can't be created, for example if both paths are not
+    /// on the same filesystem.
+    pub fn link(self, source: &Path, destination: &Path) -> io::Result<()> {
+        match self {
+            Self::Hardlink => fs::hard_link(source, destination),
+            Self::Symlink => symlink(&source.canonicalize()?, destination),
+            Self::Reflink => reflink::reflink(source, destination),
+            Self::Off => Err(io::Error::from(ErrorKind::Unsupported)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(source: &Path, destination: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source, destination)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, destination: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(source, destination)
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hardlink" => Ok(Self::Hardlink),
+            "symlink" => Ok(Self::Symlink),
+            "reflink" => Ok(Self::Reflink),
+            "off" => Ok(Self::Off),
+            _ => Err(format!(
+                "invalid mode '{}', expected hardlink, symlink, reflink or off",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hardlink => write!(f, "hardlink"),
+            Self::Symlink => write!(f, "symlink"),
+            Self::Reflink => write!(f, "reflink"),
+            Self::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// A file of the index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// Base64 encoded MD5 of the file
+    md5:  String,
+    /// Path of the file
+    path: PathBuf,
+    /// Size in bytes
+    size: u64,
+}
+
+/// A line of the index, which also records the output directories whose
+/// files have been indexed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    File(Entry),
+    Directory { directory: PathBuf },
+}
+
+/// Index of the downloaded files by MD5, shared by all the output
+/// directories.
+///
+/// A file is only linked if it is still where it was downloaded, with the
+/// same size. The first time an output directory is used, the files recorded
+/// in the [`DownloadState`] of its threads are added to the index.
+///
+/// # Examples
+///
+/// ```no_run
+/// use chan_downloader::dedup::{DedupIndex, LinkMode};
+/// use std::env;
+/// let output = env::current_dir().unwrap().join("downloads");
+/// let destination = output.join("wg/6872254/1489266570954.jpg");
+/// if let Some(path) = DedupIndex::default_path() {
+///     let mut index = DedupIndex::open(&path).unwrap();
+///     index.add_directory(&output).unwrap();
+///     if let Some(source) = index.find("Fb1y+3XGvZPxxFDPNLzvHA==", Some(1024)) {
+///         LinkMode::Hardlink.link(&source, &destination).unwrap();
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct DedupIndex {
+    journal:     Journal<Line>,
+    entries:     HashMap<String, Entry>,
+    directories: HashSet<PathBuf>,
+}
+
+impl DedupIndex {
+    /// Name of the index kept in each output directory by older versions
+    pub const LEGACY_FILE_NAME: &'static str = ".chan-downloader-index.jsonl";
+
+    /// Returns the path of the index in the data directory of the user, such
+    /// as `~/.local/share/chan-downloader/index.jsonl` on Linux
+    #[must_use]
+    pub fn default_path() -> Option<PathBuf> {
+        Some(dirs::data_dir()?.join("chan-downloader").join("index.jsonl"))
+    }
+
+    /// Loads the index at `path`, which is empty if it doesn't exist yet
+    ///
+    /// # Errors
+    ///
+    /// Fails if the index exists but can't be read, or if its directory
+    /// can't be created.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let (journal, lines) = Journal::open(path.to_owned())?;
+        let mut index = Self {
+            journal,
+            entries: HashMap::new(),
+            directories: HashSet::new(),
+        };
+        for line in lines.unwrap_or_default() {
+            match line {
+                Line::File(entry) => {
+                    index.entries.insert(entry.md5.clone(), entry);
+                },
+                Line::Directory { directory } => {
+                    index.directories.insert(directory);
+                },
+            }
+        }
+        Ok(index)
+    }
+
+    /// Adds the files downloaded to the output directory, unless they have
+    /// already been added.
+    ///
+    /// The files are taken from the index kept in the directory by older
+    /// versions, or else from the download states of its threads.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the files can't be added to the index.
+    pub fn add_directory(&mut self, root: &Path) -> Result<(), Error> {
+        if self.directories.contains(root) {
+            return Ok(());
+        }
+        let count = self.entries.len();
+        let (_, legacy) = Journal::<Entry>::open(root.join(Self::LEGACY_FILE_NAME))?;
+        match legacy {
+            Some(entries) =>
+                for entry in entries {
+                    self.insert(&entry.md5, &root.join(&entry.path), entry.size)?;
+                },
+            None => find_directories(root, &DownloadState::is_file_name, &mut |directory| {
+                for state in DownloadState::open_all(directory)? {
+                    for record in state.records() {
+                        if let Some(md5) = &record.md5 {
+                            self.insert(md5, &directory.join(&record.path), record.size)?;
+                        }
+                    }
+                }
+                Ok(())
+            })?,
+        }
+        info!(
+            "Indexed {} files downloaded under {}",
+            self.entries.len() - count,
+            root.display()
+        );
+        let directory = root.to_owned();
+        self.journal
+            .append(&Line::Directory { directory: directory.clone() })?;
+        self.directories.insert(directory);
+        Ok(())
+    }
+
+    /// Returns the path of a file with the MD5, if one is still there with
+    /// the expected size
+    #[must_use]
+    pub fn find(&self, md5: &str, size: Option<u64>) -> Option<PathBuf> {
+        let entry = self.entries.get(md5)?;
+        let metadata = fs::metadata(&entry.path).ok()?;
+        (metadata.is_file() && size.unwrap_or(entry.size) == metadata.len()).then(|| entry.path.clone())
+    }
+
+    /// Adds a downloaded file to the index
+    ///
+    /// # Errors
+    ///
+    /// Fails if the entry can't be appended to the index.
+    pub fn insert(&mut self, md5: &str, path: &Path, size: u64) -> Result<(), Error> {
+        if self.find(md5, Some(size)).is_some() {
+            return Ok(());
+        }
+        let entry = Entry {
+            md5: md5.to_owned(),
+            path: path.to_owned(),
+            size,
+        };
+        self.journal.append(&Line::File(entry.clone()))?;
+        self.entries.insert(entry.md5.clone(), entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{state::Record, tests::empty_directory};
+
+    #[test]
+    fn it_parses_link_modes() {
+        assert_eq!("hardlink".parse(), Ok(LinkMode::Hardlink));
+        assert_eq!("symlink".parse(), Ok(LinkMode::Symlink));
+        assert_eq!("reflink".parse(), Ok(LinkMode::Reflink));
+        assert_eq!("off".parse(), Ok(LinkMode::Off));
+        assert!("copy".parse::<LinkMode>().is_err());
+    }
+
+    #[test]
+    fn it_finds_duplicates() {
+        let root = empty_directory("chan-downloader-dedup");
+        let md5 = "eIBaIhqYjnnvP0LXxb/UGA==";
+        let source = root.join("wg/1/1.jpg");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, "image").unwrap();
+
+        let path = root.join("index.jsonl");
+        let mut index = DedupIndex::open(&path).unwrap();
+        assert_eq!(index.find(md5, None), None);
+        index.insert(md5, &source, 5).unwrap();
+        assert_eq!(index.find(md5, Some(5)), Some(source.clone()));
+        assert_eq!(index.find(md5, Some(6)), None);
+
+        let index = DedupIndex::open(&path).unwrap();
+        assert_eq!(index.find(md5, None), Some(source.clone()));
+
+        let destination = root.join("wg/2.jpg");
+        LinkMode::Hardlink.link(&source, &destination).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), b"image");
+        #[cfg(unix)]
+        {
+            let symlink = root.join("wg/3.jpg");
+            LinkMode::Symlink.link(&source, &symlink).unwrap();
+            assert!(fs::symlink_metadata(&symlink).unwrap().file_type().is_symlink());
+        }
+
+        fs::remove_file(&source).unwrap();
+        assert_eq!(index.find(md5, None), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_adds_output_directories() {
+        let root = empty_directory("chan-downloader-dedup-directories");
+        let md5 = "eIBaIhqYjnnvP0LXxb/UGA==";
+        let output = root.join("downloads");
+        let directory = output.join("wg/1");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("1.jpg"), "image").unwrap();
+        let mut state = DownloadState::open(&directory, "4chan.wg.1").unwrap();
+        state
+            .insert(Record {
+                url:  String::from("//i.4cdn.org/wg/1.jpg"),
+                path: String::from("1.jpg"),
+                size: 5,
+                md5:  Some(String::from(md5)),
+                post: Some(1),
+                time: 0,
+            })
+            .unwrap();
+        // Older versions kept an index in each output directory
+        let legacy = root.join("wallpapers");
+        fs::create_dir_all(legacy.join("wg/2")).unwrap();
+        fs::write(legacy.join("wg/2/2.png"), "other").unwrap();
+        fs::write(
+            legacy.join(DedupIndex::LEGACY_FILE_NAME),
+            "{\"md5\": \"2\", \"path\": \"wg/2/2.png\", \"size\": 5}\n",
+        )
+        .unwrap();
+
+        let path = root.join("index.jsonl");
+        let mut index = DedupIndex::open(&path).unwrap();
+        assert_eq!(index.find(md5, None), None);
+        index.add_directory(&output).unwrap();
+        index.add_directory(&legacy).unwrap();
+        assert_eq!(index.find(md5, Some(5)), Some(directory.join("1.jpg")));
+        assert_eq!(index.find("2", None), Some(legacy.join("wg/2/2.png")));
+
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        let mut index = DedupIndex::open(&path).unwrap();
+        index.add_directory(&output).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), lines);
+        assert_eq!(index.find(md5, None), Some(directory.join("1.jpg")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
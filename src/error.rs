@@ -0,0 +1,189 @@
+//! Errors returned by `chan_downloader`
+
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use std::{
+    io,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+/// Error returned by the functions of `chan_downloader`
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The request couldn't be sent or the response couldn't be read
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+    /// The thread or the file is gone (404)
+    #[error("{0} was not found")]
+    NotFound(String),
+    /// The server refused to serve the url (403)
+    #[error("access to {0} is forbidden")]
+    Forbidden(String),
+    /// The server asked to slow down (429)
+    #[error("too many requests sent to {url}")]
+    TooManyRequests { url: String, retry_after: Option<Duration> },
+    /// The server failed to answer (5xx)
+    #[error("{url} answered with the server error {status}")]
+    Server {
+        url:         String,
+        status:      StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// The server answered with any other unsuccessful status
+    #[error("{url} answered with {status}")]
+    Status { url: String, status: StatusCode },
+    /// The body ended before the announced length
+    #[error("{url} ended after {received} of {expected} bytes")]
+    Incomplete { url: String, expected: u64, received: u64 },
+    /// The MD5 of the file doesn't match the one given by the API
+    #[error("{url} has the MD5 {actual} instead of {expected}")]
+    Md5Mismatch { url: String, expected: String, actual: String },
+    /// The file couldn't be written
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The API content couldn't be parsed
+    #[error("failed to parse the API content: {0}")]
+    Parse(#[from] serde_json::Error),
+    /// The url doesn't point to a thread
+    #[error(transparent)]
+    ThreadUrl(#[from] ThreadUrlError),
+    /// The config file is not valid TOML
+    #[error("failed to parse the config: {0}")]
+    Toml(#[from] toml::de::Error),
+    /// The config file has invalid options
+    #[error("invalid config: {0}")]
+    Config(String),
+}
+
+impl Error {
+    /// Returns the error matching an unsuccessful response
+    pub(crate) fn from_response(url: &str, response: &Response) -> Self {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        Self::from_status(url, response.status(), retry_after)
+    }
+
+    /// Returns the error matching an unsuccessful status
+    pub(crate) fn from_status(url: &str, status: StatusCode, retry_after: Option<Duration>) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => Self::NotFound(url.to_owned()),
+            StatusCode::FORBIDDEN => Self::Forbidden(url.to_owned()),
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests {
+                url: url.to_owned(),
+                retry_after,
+            },
+            _ if status.is_server_error() => Self::Server {
+                url: url.to_owned(),
+                status,
+                retry_after,
+            },
+            _ => Self::Status { url: url.to_owned(), status },
+        }
+    }
+
+    /// Returns true if the same request may succeed later
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Network(err) => !err.is_builder() && !err.is_redirect() && !err.is_decode(),
+            Self::TooManyRequests { .. }
+            | Self::Server { .. }
+            | Self::Incomplete { .. }
+            | Self::Md5Mismatch { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns how long the server asked to wait before the next request
+    #[must_use]
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::TooManyRequests { retry_after, .. } | Self::Server { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Parses a `Retry-After` value, given either in seconds or as a date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Error returned when a url doesn't point to a thread
+#[derive(Debug, Error)]
+pub enum ThreadUrlError {
+    #[error("invalid url {url}: {source}")]
+    InvalidUrl { url: String, source: url::ParseError },
+    #[error("no imageboard handles {0}")]
+    UnsupportedHost(String),
+    #[error("{0} is not a thread url")]
+    NotAThread(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_statuses() {
+        let url = "https://a.4cdn.org/wg/thread/1.json";
+        let from_status = |status| Error::from_status(url, status, None);
+        assert!(matches!(from_status(StatusCode::NOT_FOUND), Error::NotFound(u) if u == url));
+        assert!(matches!(from_status(StatusCode::FORBIDDEN), Error::Forbidden(_)));
+        assert!(matches!(
+            from_status(StatusCode::TOO_MANY_REQUESTS),
+            Error::TooManyRequests { .. }
+        ));
+        assert!(matches!(from_status(StatusCode::BAD_GATEWAY), Error::Server {
+            status: StatusCode::BAD_GATEWAY,
+            ..
+        }));
+        assert!(matches!(from_status(StatusCode::GONE), Error::Status {
+            status: StatusCode::GONE,
+            ..
+        }));
+        assert_eq!(
+            from_status(StatusCode::BAD_GATEWAY).to_string(),
+            "https://a.4cdn.org/wg/thread/1.json answered with the server error 502 Bad Gateway"
+        );
+    }
+
+    #[test]
+    fn it_tells_transient_errors() {
+        let url = "https://i.4cdn.org/wg/1.jpg";
+        let retry_after = Some(Duration::from_secs(3));
+        assert!(Error::from_status(url, StatusCode::SERVICE_UNAVAILABLE, retry_after).is_transient());
+        assert!(Error::from_status(url, StatusCode::TOO_MANY_REQUESTS, None).is_transient());
+        assert!(!Error::from_status(url, StatusCode::NOT_FOUND, None).is_transient());
+        assert!(!Error::from_status(url, StatusCode::FORBIDDEN, None).is_transient());
+        assert!(Error::Md5Mismatch {
+            url:      url.to_owned(),
+            expected: String::from("Fb1y+3XGvZPxxFDPNLzvHA=="),
+            actual:   String::from("eIBaIhqYjnnvP0LXxb/UGA=="),
+        }
+        .is_transient());
+        assert_eq!(
+            Error::from_status(url, StatusCode::SERVICE_UNAVAILABLE, retry_after).retry_after(),
+            retry_after
+        );
+    }
+
+    #[test]
+    fn it_parses_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(600));
+        assert!(parse_retry_after(&later).unwrap() > Duration::from_secs(500));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! Selection of the files to download from their metadata and their post
+
+use crate::{post::File, Link, Post};
+use regex::Regex;
+use std::{fmt, str::FromStr};
+
+/// Margin allowed around the bounds of an aspect ratio range, so `16:9`
+/// also matches 1366x768
+const RATIO_TOLERANCE: f64 = 0.01;
+
+/// Files to download, selected from the metadata given by the API before
+/// anything is requested.
+///
+/// Extensions are given without the dot, in any case. A file is kept if it
+/// passes every condition that is set. Sizes and dimensions that the API
+/// doesn't give, such as the dimensions of the archived files, don't exclude
+/// anything.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::filter::MediaFilter;
+/// let filter = MediaFilter {
+///     exclude_extensions: vec![String::from("gif")],
+///     min_width: Some(1920),
+///     min_height: Some(1080),
+///     aspect_ratios: vec!["landscape".parse().unwrap()],
+///     ..MediaFilter::default()
+/// };
+/// assert!(!filter.is_empty());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaFilter {
+    /// Extensions to download, or every extension when empty
+    pub extensions:         Vec<String>,
+    /// Extensions never downloaded
+    pub exclude_extensions: Vec<String>,
+    /// Smallest size in bytes
+    pub min_size:           Option<u64>,
+    /// Largest size in bytes
+    pub max_size:           Option<u64>,
+    pub min_width:          Option<u32>,
+    pub max_width:          Option<u32>,
+    pub min_height:         Option<u32>,
+    pub max_height:         Option<u32>,
+    /// Aspect ratios to download, or every ratio when empty
+    pub aspect_ratios:      Vec<AspectRatio>,
+}
+
+impl MediaFilter {
+    /// Returns true if the filter keeps every file
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns true if the file of the link should be downloaded.
+    ///
+    /// Links without metadata are only checked by the extension of their url.
+    #[must_use]
+    pub fn matches_link(&self, link: &Link) -> bool {
+        match &link.file {
+            Some(file) => self.matches(file),
+            None => {
+                let ext = link.url.rsplit_once('.').map_or("", |(_, ext)| ext);
+                self.matches_extension(ext)
+            },
+        }
+    }
+
+    /// Returns true if the file should be downloaded
+    #[must_use]
+    pub fn matches(&self, file: &File) -> bool {
+        let size = Some(file.fsize).filter(|size| *size > 0);
+        let width = Some(file.w).filter(|width| *width > 0);
+        let height = Some(file.h).filter(|height| *height > 0);
+        self.matches_extension(&file.ext)
+            && within(size, self.min_size, self.max_size)
+            && within(width, self.min_width, self.max_width)
+            && within(height, self.min_height, self.max_height)
+            && match (width, height) {
+                (Some(width), Some(height)) if !self.aspect_ratios.is_empty() => self
+                    .aspect_ratios
+                    .iter()
+                    .any(|ratio| ratio.matches(width, height)),
+                _ => true,
+            }
+    }
+
+    fn matches_extension(&self, ext: &str) -> bool {
+        let ext = ext.trim_start_matches('.');
+        let listed = |extensions: &[String]| {
+            extensions
+                .iter()
+                .any(|listed| listed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        };
+        (self.extensions.is_empty() || listed(&self.extensions)) && !listed(&self.exclude_extensions)
+    }
+}
+
+/// Returns true if the value is unknown or between the bounds
+fn within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    match value {
+        Some(value) =>
+            !matches!(&min, Some(min) if value < *min) && !matches!(&max, Some(max) if value > *max),
+        None => true,
+    }
+}
+
+/// Aspect ratio of the images or videos to download
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AspectRatio {
+    /// Wider than high
+    Landscape,
+    /// Higher than wide
+    Portrait,
+    Square,
+    /// Width divided by height between the bounds, included
+    Range {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+}
+
+impl AspectRatio {
+    /// Returns true if an image of the dimensions has the aspect ratio
+    #[must_use]
+    pub fn matches(self, width: u32, height: u32) -> bool {
+        match self {
+            Self::Landscape => width > height,
+            Self::Portrait => width < height,
+            Self::Square => width == height,
+            Self::Range { min, max } => {
+                let ratio = f64::from(width) / f64::from(height);
+                !matches!(min, Some(min) if ratio < min - RATIO_TOLERANCE)
+                    && !matches!(max, Some(max) if ratio > max + RATIO_TOLERANCE)
+            },
+        }
+    }
+}
+
+impl FromStr for AspectRatio {
+    type Err = String;
+
+    /// Parses `landscape`, `portrait`, `square`, a ratio such as `16:9` or
+    /// `1.5`, or a range of ratios such as `16:10-21:9`, `4:3-` or `-1`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid aspect ratio '{}'", value);
+        let ratio = |ratio: &str| -> Result<Option<f64>, String> {
+            let ratio = ratio.trim();
+            if ratio.is_empty() {
+                return Ok(None);
+            }
+            let parsed = match ratio.split_once(':') {
+                Some((width, height)) => {
+                    let width: f64 = width.parse().map_err(|_| invalid())?;
+                    let height: f64 = height.parse().map_err(|_| invalid())?;
+                    width / height
+                },
+                None => ratio.parse().map_err(|_| invalid())?,
+            };
+            if parsed.is_finite() && parsed > 0.0 {
+                Ok(Some(parsed))
+            } else {
+                Err(invalid())
+            }
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "landscape" => Ok(Self::Landscape),
+            "portrait" => Ok(Self::Portrait),
+            "square" => Ok(Self::Square),
+            range => {
+                let (min, max) = match range.split_once('-') {
+                    Some((min, max)) => (ratio(min)?, ratio(max)?),
+                    None => {
+                        let exact = ratio(range)?;
+                        (exact, exact)
+                    },
+                };
+                match (min, max) {
+                    (None, None) => Err(invalid()),
+                    (Some(min), Some(max)) if min > max => Err(invalid()),
+                    _ => Ok(Self::Range { min, max }),
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for AspectRatio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Landscape => write!(f, "landscape"),
+            Self::Portrait => write!(f, "portrait"),
+            Self::Square => write!(f, "square"),
+            Self::Range { min, max } => {
+                let bound =
+                    |bound: &Option<f64>| bound.map(|bound| bound.to_string()).unwrap_or_default();
+                write!(f, "{}-{}", bound(min), bound(max))
+            },
+        }
+    }
+}
+
+/// Posts whose files are downloaded, selected by their content.
+///
+/// A post is kept if it matches one of the `include` rules, or if there are
+/// none, and none of the `exclude` rules.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::filter::PostFilter;
+/// let filter = PostFilter {
+///     include: vec!["op".parse().unwrap(), "comment:(?i)source".parse().unwrap()],
+///     exclude: vec!["id:Bx9fJ2kL".parse().unwrap()],
+/// };
+/// assert!(!filter.is_empty());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostFilter {
+    pub include: Vec<PostRule>,
+    pub exclude: Vec<PostRule>,
+}
+
+impl PostFilter {
+    /// Returns true if the filter keeps every post
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if the files of the post should be downloaded. `op` is
+    /// the first post of the thread.
+    #[must_use]
+    pub fn matches(&self, post: &Post, op: &Post) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(post, op)))
+            && !self.exclude.iter().any(|rule| rule.matches(post, op))
+    }
+}
+
+/// Conditions that a post must all match, separated by `&&`, such as
+/// `trip:!Ep8pui8Vw2 && comment:(?i)\bOC\b`.
+///
+/// The conditions are:
+/// - `op`: posted by the OP, which is the first post, or a reply with the
+///   poster ID or the tripcode of the first post
+/// - `comment:REGEX`: comment matching the regex, without its HTML
+/// - `id:ID`: poster ID, on the boards that show them
+/// - `trip:TRIPCODE`: tripcode, with or without the leading `!`
+/// - `name:NAME`: name of the poster
+#[derive(Debug, Clone)]
+pub struct PostRule {
+    source:     String,
+    conditions: Vec<PostCondition>,
+}
+
+#[derive(Debug, Clone)]
+enum PostCondition {
+    Op,
+    Comment(Regex),
+    Id(String),
+    Tripcode(String),
+    Name(String),
+}
+
+impl PostRule {
+    /// Returns true if the post matches every condition of the rule
+    #[must_use]
+    pub fn matches(&self, post: &Post, op: &Post) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            PostCondition::Op =>
+                post.no == op.no
+                    || (op.id.is_some() && post.id == op.id)
+                    || (op.tripcode.is_some() && post.tripcode == op.tripcode),
+            PostCondition::Comment(regex) => {
+                matches!(post.comment_text(), Some(comment) if regex.is_match(&comment))
+            },
+            PostCondition::Id(id) => post.id.as_deref() == Some(id.as_str()),
+            PostCondition::Tripcode(tripcode) => matches!(
+                &post.tripcode,
+                Some(trip) if without_mark(trip) == without_mark(tripcode)
+            ),
+            PostCondition::Name(name) => post.name.as_deref() == Some(name.as_str()),
+        })
+    }
+}
+
+/// Returns the tripcode without its leading `!`, keeping the second `!` that
+/// marks a secure tripcode
+fn without_mark(tripcode: &str) -> &str {
+    tripcode.strip_prefix('!').unwrap_or(tripcode)
+}
+
+impl PartialEq for PostRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl FromStr for PostRule {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let conditions = source
+            .split("&&")
+            .map(|condition| {
+                let condition = condition.trim();
+                let (key, value) = condition.split_once(':').unwrap_or((condition, ""));
+                let value = value.trim();
+                if value.is_empty() && key != "op" {
+                    return Err(format!("invalid post rule '{}'", condition));
+                }
+                match key {
+                    "op" if value.is_empty() => Ok(PostCondition::Op),
+                    "comment" => Regex::new(value)
+                        .map(PostCondition::Comment)
+                        .map_err(|err| err.to_string()),
+                    "id" => Ok(PostCondition::Id(value.to_owned())),
+                    "trip" => Ok(PostCondition::Tripcode(value.to_owned())),
+                    "name" => Ok(PostCondition::Name(value.to_owned())),
+                    _ => Err(format!(
+                        "invalid post rule '{}', expected op, comment:, id:, trip: or name:",
+                        condition
+                    )),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            source: source.to_owned(),
+            conditions,
+        })
+    }
+}
+
+impl fmt::Display for PostRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Parses a size in bytes such as `500`, `200k`, `5MB` or `1.5G`, with
+/// binary units
+///
+/// # Errors
+///
+/// Fails if the size is not a positive number or uses an unknown unit.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::filter::parse_size;
+///
+/// assert_eq!(parse_size("200k"), Ok(200 * 1024));
+/// assert_eq!(parse_size("4MB"), Ok(4 * 1024 * 1024));
+/// ```
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size '{}'", value))?;
+    let unit = match unit.trim().to_ascii_lowercase().trim_end_matches('b') {
+        "" => 1,
+        "k" | "ki" => 1 << 10,
+        "m" | "mi" => 1 << 20,
+        "g" | "gi" => 1 << 30,
+        _ => return Err(format!("invalid unit in size '{}'", value)),
+    };
+    Ok((number * f64::from(unit)).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(ext: &str, fsize: u64, w: u32, h: u32) -> File {
+        File {
+            tim: 1489266570954,
+            filename: String::from("file"),
+            ext: ext.to_owned(),
+            fsize,
+            md5: String::new(),
+            w,
+            h,
+            tn_w: 0,
+            tn_h: 0,
+            spoiler: false,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn it_filters_files() {
+        let filter = MediaFilter {
+            exclude_extensions: vec![String::from("GIF")],
+            max_size: Some(8 << 20),
+            min_width: Some(1920),
+            min_height: Some(1080),
+            aspect_ratios: vec![AspectRatio::Landscape],
+            ..MediaFilter::default()
+        };
+        assert!(filter.matches(&file(".jpg", 1 << 20, 1920, 1080)));
+        assert!(filter.matches(&file(".png", 1 << 20, 3840, 1600)));
+        assert!(!filter.matches(&file(".gif", 1 << 20, 1920, 1080)));
+        assert!(!filter.matches(&file(".jpg", 9 << 20, 1920, 1080)));
+        assert!(!filter.matches(&file(".jpg", 1 << 20, 1280, 720)));
+        assert!(!filter.matches(&file(".jpg", 1 << 20, 1080, 1920)));
+        assert!(!filter.matches(&file(".jpg", 1 << 20, 2000, 2000)));
+        // Unknown metadata doesn't exclude the file
+        assert!(filter.matches(&file(".jpg", 0, 0, 0)));
+
+        let only_webm = MediaFilter {
+            extensions: vec![String::from("webm")],
+            ..MediaFilter::default()
+        };
+        assert!(only_webm.matches(&file(".webm", 1, 1, 1)));
+        assert!(!only_webm.matches(&file(".jpg", 1, 1, 1)));
+        let link = Link {
+            url:  String::from("//i.4cdn.org/wg/1489266570954.jpg"),
+            name: String::from("1489266570954.jpg"),
+            post: None,
+            file: None,
+        };
+        assert!(!only_webm.matches_link(&link));
+        assert!(MediaFilter::default().matches_link(&link));
+        assert!(MediaFilter::default().is_empty());
+        assert!(!only_webm.is_empty());
+    }
+
+    #[test]
+    fn it_parses_aspect_ratios() {
+        let ratio = |value: &str| value.parse::<AspectRatio>().unwrap();
+        assert_eq!(ratio("Landscape"), AspectRatio::Landscape);
+        assert!(ratio("16:9").matches(1920, 1080));
+        assert!(ratio("16:9").matches(1366, 768));
+        assert!(!ratio("16:9").matches(1920, 1200));
+        assert!(ratio("16:10-21:9").matches(1920, 1200));
+        assert!(ratio("16:10-21:9").matches(2520, 1080));
+        assert!(!ratio("16:10-21:9").matches(3840, 1080));
+        assert!(!ratio("16:10-21:9").matches(1600, 1200));
+        assert!(ratio("1.5-").matches(3000, 1000));
+        assert!(!ratio("-1").matches(1920, 1080));
+        assert_eq!(ratio("4:3-").to_string(), format!("{}-", 4.0 / 3.0));
+        for invalid in ["", "-", "wide", "16:0", "21:9-16:9", "0"] {
+            assert!(invalid.parse::<AspectRatio>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_filters_posts() {
+        let posts = crate::get_posts(
+            r#"{"posts": [
+                {"no": 1, "time": 0, "id": "Aa1", "trip": "!Ep8pui8Vw2", "com": "OC inside"},
+                {"no": 2, "time": 0, "id": "Bb2", "com": "<a>&gt;&gt;1</a><br>source?"},
+                {"no": 3, "time": 0, "id": "Aa1", "com": "Source: me"},
+                {"no": 4, "time": 0, "id": "Cc3", "name": "Anon", "trip": "!Ep8pui8Vw2"},
+                {"no": 5, "time": 0, "id": "Dd4", "trip": "!!Ep8pui8Vw2"}
+            ]}"#,
+        )
+        .unwrap();
+        let kept = |filter: &PostFilter| -> Vec<u64> {
+            posts
+                .iter()
+                .filter(|post| filter.matches(post, &posts[0]))
+                .map(|post| post.no)
+                .collect()
+        };
+        let rule = |rule: &str| rule.parse::<PostRule>().unwrap();
+
+        assert_eq!(kept(&PostFilter::default()), [1, 2, 3, 4, 5]);
+        let op = PostFilter {
+            include: vec![rule("op")],
+            exclude: Vec::new(),
+        };
+        assert_eq!(kept(&op), [1, 3, 4]);
+        let source = PostFilter {
+            include: vec![rule("comment:(?im)^source")],
+            exclude: vec![rule("op && id:Aa1")],
+        };
+        assert_eq!(kept(&source), [2]);
+        let combined = PostFilter {
+            include: vec![rule("trip:Ep8pui8Vw2 && name:Anon"), rule("id:Bb2")],
+            exclude: vec![rule("comment:OC")],
+        };
+        assert_eq!(kept(&combined), [2, 4]);
+        let secure = PostFilter {
+            include: vec![rule("trip:!!Ep8pui8Vw2")],
+            exclude: Vec::new(),
+        };
+        assert_eq!(kept(&secure), [5]);
+
+        for invalid in ["", "id:", "comment:(", "op:1", "poster:Aa1", "op && "] {
+            assert!(invalid.parse::<PostRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_parses_sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500B"), Ok(500));
+        assert_eq!(parse_size("200k"), Ok(200 * 1024));
+        assert_eq!(parse_size("1.5MiB"), Ok(3 << 19));
+        assert_eq!(parse_size("2 GB"), Ok(2 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("5TB").is_err());
+    }
+}
@@ -0,0 +1,452 @@
+//! Imageboard backends
+//!
+//! Every site implements [`Imageboard`] and is registered in a [`Registry`],
+//! which picks the backend handling the host of a thread url.
+
+use crate::{
+    error::ThreadUrlError,
+    post::{File, Post},
+    template::{FileContext, FilenameTemplate},
+    Link,
+    Thread,
+};
+use log::info;
+use reqwest::Url;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+mod fourchan;
+mod fourplebs;
+
+pub use fourchan::FourChan;
+pub use fourplebs::FourPlebs;
+
+/// A site threads can be downloaded from
+pub trait Imageboard: Send + Sync {
+    /// Name of the site, used in messages
+    fn name(&self) -> &str;
+
+    /// Returns true if the host is served by this site
+    fn handles(&self, host: &str) -> bool;
+
+    /// Returns the thread the url points to
+    fn parse_thread_url(&self, url: &Url) -> Result<Thread, ThreadUrlError>;
+
+    /// Returns the url of the thread API
+    fn api_url(&self, thread: &Thread) -> String;
+
+    /// Returns the url of the thread page
+    fn page_url(&self, thread: &Thread) -> String;
+
+    /// Returns the posts found in the content of the thread API
+    fn get_posts(&self, thread: &Thread, content: &str) -> Result<Vec<Post>, serde_json::Error>;
+
+    /// Returns the url of the catalog API of the board, if the site has one
+    fn catalog_url(&self, _board: &str) -> Option<String> {
+        None
+    }
+
+    /// Returns the OPs of the threads found in the content of the catalog API
+    fn get_catalog(&self, _content: &str) -> Result<Vec<Post>, serde_json::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the url of the file, without the scheme
+    fn media_url(&self, thread: &Thread, file: &File) -> String;
+
+    /// Returns the links of the files found in the content of the thread API.
+    ///
+    /// The links are named by rendering the template with the metadata of
+    /// their post. Names used more than once in the thread get the server
+    /// timestamp appended to stay unique.
+    fn get_links(
+        &self,
+        thread: &Thread,
+        content: &str,
+        template: &FilenameTemplate,
+    ) -> Result<Vec<Link>, serde_json::Error> {
+        let posts = self.get_posts(thread, content)?;
+        Ok(self.get_post_links(thread, &posts, template))
+    }
+
+    /// Returns the links of the files attached to the posts.
+    ///
+    /// See [`get_links`](Imageboard::get_links) for the naming of the links.
+    fn get_post_links(&self, thread: &Thread, posts: &[Post], template: &FilenameTemplate) -> Vec<Link> {
+        info!(target: "link_events", "Getting image links from {}", self.name());
+        let mut used_names = HashSet::new();
+        let links_v: Vec<Link> = posts
+            .iter()
+            .filter_map(|post| Some((post, post.file.as_ref()?)))
+            .enumerate()
+            .filter(|(_, (_, file))| !file.deleted)
+            .map(|(index, (post, file))| {
+                let context = FileContext {
+                    thread,
+                    post,
+                    file,
+                    index: index + 1,
+                };
+                let mut name = template.render(&context);
+                if !used_names.insert(name.clone()) {
+                    name = unique_name(&name, file, |name| !used_names.insert(name.to_owned()));
+                }
+                Link {
+                    url: self.media_url(thread, file),
+                    name,
+                    post: Some(post.no),
+                    file: Some(file.clone()),
+                }
+            })
+            .collect();
+        info!("Got {} image links from {}", links_v.len(), self.name());
+        links_v
+    }
+}
+
+/// Renames the links whose name is already taken in their directory by the
+/// file of another url, such as a file of another thread saved to the same
+/// directory. `taken` gives the url of the file recorded under each name.
+/// Like the names used more than once in a thread, they get the server
+/// timestamp appended.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{imageboard::rename_taken_links, Link};
+/// use std::collections::HashMap;
+/// let mut links = vec![Link {
+///     url:  String::from("//i.4cdn.org/wg/1489266570954.jpg"),
+///     name: String::from("1489266570954.jpg"),
+///     post: None,
+///     file: None,
+/// }];
+/// rename_taken_links(&mut links, &HashMap::new());
+/// assert_eq!(links[0].name, "1489266570954.jpg");
+/// ```
+pub fn rename_taken_links(links: &mut [Link], taken: &HashMap<String, String>) {
+    if taken.is_empty() {
+        return;
+    }
+    let mut used = links
+        .iter()
+        .map(|link| link.name.clone())
+        .chain(taken.keys().cloned())
+        .collect::<HashSet<_>>();
+    for link in links.iter_mut() {
+        if !matches!(taken.get(&link.name), Some(url) if *url != link.url) {
+            continue;
+        }
+        if let Some(file) = &link.file {
+            link.name = unique_name(&link.name, file, |name| !used.insert(name.to_owned()));
+        }
+    }
+}
+
+/// Returns the name to save the file of the link under in the directory:
+/// the name of the link, or a new one if a file of the directory or another
+/// download already has it. The path is added to `reserved`, which holds the
+/// paths of the downloads in progress, so that two downloads never write to
+/// the same file. Links without a file keep their name.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{imageboard::reserve_name, Link};
+/// use std::{collections::HashSet, env};
+/// let directory = env::current_dir().unwrap().join("downloads/wg/6872254");
+/// let link = Link {
+///     url:  String::from("//i.4cdn.org/wg/1489266570954.jpg"),
+///     name: String::from("1489266570954.jpg"),
+///     post: None,
+///     file: None,
+/// };
+/// let mut reserved = HashSet::new();
+/// assert_eq!(
+///     reserve_name(&directory, &link, &mut reserved),
+///     "1489266570954.jpg"
+/// );
+/// assert!(reserved.contains(&directory.join("1489266570954.jpg")));
+/// ```
+pub fn reserve_name(directory: &Path, link: &Link, reserved: &mut HashSet<PathBuf>) -> String {
+    let is_taken = |name: &str| {
+        let path = directory.join(name);
+        reserved.contains(&path) || path.exists()
+    };
+    let name = match &link.file {
+        Some(file) if is_taken(&link.name) => unique_name(&link.name, file, is_taken),
+        _ => link.name.clone(),
+    };
+    reserved.insert(directory.join(&name));
+    name
+}
+
+/// Returns the name with the server timestamp of the file appended, and a
+/// counter if that is still taken
+fn unique_name(name: &str, file: &File, mut is_taken: impl FnMut(&str) -> bool) -> String {
+    let stem = name.strip_suffix(&file.ext).unwrap_or(name);
+    let mut unique = format!("{} ({}){}", stem, file.tim, file.ext);
+    let mut count = 1;
+    while is_taken(&unique) {
+        count += 1;
+        unique = format!("{} ({}-{}){}", stem, file.tim, count, file.ext);
+    }
+    unique
+}
+
+/// Collection of the known imageboards
+pub struct Registry {
+    boards: Vec<Box<dyn Imageboard>>,
+}
+
+impl Registry {
+    /// Returns a registry without any imageboard
+    #[must_use]
+    pub fn new() -> Self {
+        Self { boards: Vec::new() }
+    }
+
+    /// Adds an imageboard to the registry
+    pub fn register(&mut self, board: impl Imageboard + 'static) {
+        self.boards.push(Box::new(board));
+    }
+
+    /// Returns the imageboard handling the host of the url.
+    /// The scheme of the url can be left out.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chan_downloader::imageboard::Registry;
+    ///
+    /// let registry = Registry::default();
+    /// let board = registry
+    ///     .find("https://archive.4plebs.org/x/thread/32661196")
+    ///     .unwrap();
+    ///
+    /// assert_eq!(board.name(), "4plebs");
+    /// ```
+    #[must_use]
+    pub fn find(&self, url: &str) -> Option<&dyn Imageboard> {
+        let url = parse_url(url).ok()?;
+        self.find_by_host(url.host_str()?)
+    }
+
+    fn find_by_host(&self, host: &str) -> Option<&dyn Imageboard> {
+        self.boards
+            .iter()
+            .find(|board| board.handles(host))
+            .map(AsRef::as_ref)
+    }
+
+    /// Returns the imageboard handling the url and the thread it points to
+    ///
+    /// # Errors
+    ///
+    /// Fails if the url can't be parsed, if no imageboard handles its host or
+    /// if it doesn't point to a thread.
+    pub fn resolve(&self, url: &str) -> Result<(&dyn Imageboard, Thread), ThreadUrlError> {
+        let parsed = parse_url(url)?;
+        let board = parsed
+            .host_str()
+            .and_then(|host| self.find_by_host(host))
+            .ok_or_else(|| ThreadUrlError::UnsupportedHost(url.to_owned()))?;
+        let thread = board.parse_thread_url(&parsed)?;
+        Ok((board, thread))
+    }
+}
+
+impl Default for Registry {
+    /// Returns a registry with 4chan and 4plebs
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(FourChan);
+        registry.register(FourPlebs);
+        registry
+    }
+}
+
+/// Parses the url, defaulting to https when the scheme is left out
+fn parse_url(url: &str) -> Result<Url, ThreadUrlError> {
+    let url = url.trim();
+    let full_url = if url.starts_with("//") {
+        format!("https:{}", url)
+    } else if url.contains("://") {
+        url.to_owned()
+    } else {
+        format!("https://{}", url)
+    };
+    Url::parse(&full_url).map_err(|source| ThreadUrlError::InvalidUrl { url: url.to_owned(), source })
+}
+
+/// Returns the thread from the path of the url.
+///
+/// Accepts `/board/thread/id` followed by an optional slug, the older
+/// `/board/res/id.html`, and `.json` API paths.
+pub(crate) fn parse_thread_path(url: &Url) -> Result<Thread, ThreadUrlError> {
+    let not_a_thread = || ThreadUrlError::NotAThread(url.to_string());
+    let mut segments = url.path_segments().ok_or_else(not_a_thread)?;
+    let board = segments
+        .next()
+        .filter(|board| is_board_name(board))
+        .ok_or_else(not_a_thread)?;
+    match segments.next() {
+        Some("thread" | "res") => {},
+        _ => return Err(not_a_thread()),
+    }
+    let id = segments
+        .next()
+        .map(|id| id.trim_end_matches(".json").trim_end_matches(".html"))
+        .and_then(parse_thread_id)
+        .ok_or_else(not_a_thread)?;
+    Ok(Thread { board: board.to_owned(), id })
+}
+
+pub(crate) fn is_board_name(board: &str) -> bool {
+    !board.is_empty() && board.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub(crate) fn parse_thread_id(id: &str) -> Option<u32> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::empty_directory;
+    use std::fs;
+
+    #[test]
+    fn it_finds_imageboards() {
+        let registry = Registry::default();
+        let find = |url| registry.find(url).map(Imageboard::name);
+        assert_eq!(find("https://boards.4chan.org/wg/thread/6872254"), Some("4chan"));
+        assert_eq!(find("https://boards.4channel.org/g/thread/1"), Some("4chan"));
+        assert_eq!(find("https://a.4cdn.org/po/thread/570368.json"), Some("4chan"));
+        assert_eq!(
+            find("https://archive.4plebs.org/x/thread/32661196"),
+            Some("4plebs")
+        );
+        assert_eq!(find("https://example.org/wg/thread/1"), None);
+        assert!(Registry::new()
+            .find("https://boards.4chan.org/wg/thread/1")
+            .is_none());
+
+        let (board, thread) = registry
+            .resolve("https://boards.4chan.org/wg/thread/6872254")
+            .unwrap();
+        assert_eq!(board.name(), "4chan");
+        assert_eq!(thread, Thread {
+            board: String::from("wg"),
+            id:    6872254,
+        });
+        assert!(matches!(
+            registry.resolve("https://boards.4chan.org/wg/catalog"),
+            Err(ThreadUrlError::NotAThread(_))
+        ));
+        assert!(matches!(
+            registry.resolve("https://example.org/wg/thread/1"),
+            Err(ThreadUrlError::UnsupportedHost(_))
+        ));
+        assert!(matches!(
+            registry.resolve("https://boards.4chan.org:99999/wg/thread/1"),
+            Err(ThreadUrlError::InvalidUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn it_renames_duplicated_filenames() {
+        let thread = Thread {
+            board: String::from("wg"),
+            id:    6872254,
+        };
+        let content = r#"{"posts": [
+            {"no": 6872254, "tim": 1489266570954, "filename": "stickyop", "ext": ".jpg"},
+            {"no": 6872255},
+            {"no": 6872256, "tim": 1489266570955, "filename": "stickyop", "ext": ".jpg"},
+            {"no": 6872257, "tim": 1489266570956, "filename": "a/b", "ext": ".png"},
+            {"no": 6872258, "tim": 1489266570957, "filename": "gone", "ext": ".png", "filedeleted": 1}
+        ]}"#;
+        let names: Vec<String> = FourChan
+            .get_links(&thread, content, &FilenameTemplate::original())
+            .unwrap()
+            .into_iter()
+            .map(|link| link.name)
+            .collect();
+        assert_eq!(names, ["stickyop.jpg", "stickyop (1489266570955).jpg", "a_b.png"]);
+
+        let links = FourChan
+            .get_links(&thread, content, &FilenameTemplate::server())
+            .unwrap();
+        assert_eq!(links[1].url, "//i.4cdn.org/wg/1489266570955.jpg");
+        assert_eq!(links[1].name, "1489266570955.jpg");
+
+        let template = "{index}.{ext}".parse().unwrap();
+        let links = FourChan.get_links(&thread, content, &template).unwrap();
+        assert_eq!(links[1].name, "2.jpg");
+        assert_eq!(links[2].name, "3.png");
+
+        let mut links = FourChan
+            .get_links(&thread, content, &FilenameTemplate::original())
+            .unwrap();
+        let taken = [
+            ("stickyop.jpg", "//i.4cdn.org/po/1.jpg"),
+            ("a_b.png", "//i.4cdn.org/wg/1489266570956.png"),
+            ("a_b (1489266570956).png", "//i.4cdn.org/po/2.png"),
+        ]
+        .iter()
+        .map(|(name, url)| (name.to_string(), url.to_string()))
+        .collect();
+        rename_taken_links(&mut links, &taken);
+        let names: Vec<&str> = links.iter().map(|link| link.name.as_str()).collect();
+        assert_eq!(names, [
+            "stickyop (1489266570954).jpg",
+            "stickyop (1489266570955).jpg",
+            "a_b.png"
+        ]);
+    }
+
+    #[test]
+    fn it_reserves_names() {
+        let directory = empty_directory("chan-downloader-reserve");
+        let thread = Thread {
+            board: String::from("wg"),
+            id:    6872254,
+        };
+        let content = r#"{"posts": [
+            {"no": 6872254, "tim": 1489266570954, "filename": "stickyop", "ext": ".jpg"},
+            {"no": 6872255, "tim": 1489266570955, "filename": "wallpaper", "ext": ".png"}
+        ]}"#;
+        let links = FourChan
+            .get_links(&thread, content, &FilenameTemplate::original())
+            .unwrap();
+        // A file no download state knows about has the name of the new post
+        fs::write(directory.join("stickyop.jpg"), "other").unwrap();
+
+        let mut reserved = HashSet::new();
+        assert_eq!(
+            reserve_name(&directory, &links[0], &mut reserved),
+            "stickyop (1489266570954).jpg"
+        );
+        // Another thread saving to the directory at the same time
+        assert_eq!(
+            reserve_name(&directory, &links[0], &mut reserved),
+            "stickyop (1489266570954-2).jpg"
+        );
+        assert_eq!(
+            reserve_name(&directory, &links[1], &mut reserved),
+            "wallpaper.png"
+        );
+        assert_eq!(reserved.len(), 3);
+        assert_eq!(
+            fs::read_to_string(directory.join("stickyop.jpg")).unwrap(),
+            "other"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
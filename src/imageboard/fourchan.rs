@@ -0,0 +1,78 @@
+use super::{parse_thread_path, Imageboard};
+use crate::{
+    error::ThreadUrlError,
+    post::{get_catalog_posts, get_posts, File, Post},
+    Thread,
+};
+use reqwest::Url;
+
+/// 4chan, read through its JSON API.
+///
+/// See <https://github.com/4chan/4chan-API>
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FourChan;
+
+impl Imageboard for FourChan {
+    fn name(&self) -> &str {
+        "4chan"
+    }
+
+    fn handles(&self, host: &str) -> bool {
+        ["4chan.org", "4channel.org", "4cdn.org"]
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+    }
+
+    fn parse_thread_url(&self, url: &Url) -> Result<Thread, ThreadUrlError> {
+        parse_thread_path(url)
+    }
+
+    fn api_url(&self, thread: &Thread) -> String {
+        format!("https://a.4cdn.org/{}/thread/{}.json", thread.board, thread.id)
+    }
+
+    fn page_url(&self, thread: &Thread) -> String {
+        format!("https://boards.4chan.org/{}/thread/{}", thread.board, thread.id)
+    }
+
+    fn get_posts(&self, _thread: &Thread, content: &str) -> Result<Vec<Post>, serde_json::Error> {
+        get_posts(content)
+    }
+
+    fn catalog_url(&self, board: &str) -> Option<String> {
+        Some(format!("https://a.4cdn.org/{}/catalog.json", board))
+    }
+
+    fn get_catalog(&self, content: &str) -> Result<Vec<Post>, serde_json::Error> {
+        get_catalog_posts(content)
+    }
+
+    fn media_url(&self, thread: &Thread, file: &File) -> String {
+        format!("//i.4cdn.org/{}/{}", thread.board, file.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_4chan_urls() {
+        let thread = Thread {
+            board: String::from("po"),
+            id:    570368,
+        };
+        assert_eq!(
+            FourChan.api_url(&thread),
+            "https://a.4cdn.org/po/thread/570368.json"
+        );
+        assert_eq!(
+            FourChan.page_url(&thread),
+            "https://boards.4chan.org/po/thread/570368"
+        );
+        assert_eq!(
+            FourChan.catalog_url("po").as_deref(),
+            Some("https://a.4cdn.org/po/catalog.json")
+        );
+    }
+}
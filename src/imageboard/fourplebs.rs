@@ -0,0 +1,113 @@
+use super::{is_board_name, parse_thread_id, parse_thread_path, Imageboard};
+use crate::{
+    error::ThreadUrlError,
+    post::{get_foolfuuka_posts, File, Post},
+    Thread,
+};
+use reqwest::Url;
+
+/// 4plebs, read through its FoolFuuka API.
+///
+/// See <https://4plebs.tech/foolfuuka/>
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FourPlebs;
+
+impl Imageboard for FourPlebs {
+    fn name(&self) -> &str {
+        "4plebs"
+    }
+
+    fn handles(&self, host: &str) -> bool {
+        host == "4plebs.org" || host.ends_with(".4plebs.org")
+    }
+
+    fn parse_thread_url(&self, url: &Url) -> Result<Thread, ThreadUrlError> {
+        if !url.path().starts_with("/_/api/") {
+            return parse_thread_path(url);
+        }
+
+        // API url: /_/api/chan/thread/?board=x&num=32661196
+        let query = |key| url.query_pairs().find(|(k, _)| k == key).map(|(_, v)| v);
+        let board = query("board").filter(|board| is_board_name(board));
+        let id = query("num").and_then(|id| parse_thread_id(&id));
+        match (board, id) {
+            (Some(board), Some(id)) => Ok(Thread { board: board.into_owned(), id }),
+            _ => Err(ThreadUrlError::NotAThread(url.to_string())),
+        }
+    }
+
+    fn api_url(&self, thread: &Thread) -> String {
+        format!(
+            "https://archive.4plebs.org/_/api/chan/thread/?board={}&num={}",
+            thread.board, thread.id
+        )
+    }
+
+    fn page_url(&self, thread: &Thread) -> String {
+        format!(
+            "https://archive.4plebs.org/{}/thread/{}/",
+            thread.board, thread.id
+        )
+    }
+
+    fn get_posts(&self, thread: &Thread, content: &str) -> Result<Vec<Post>, serde_json::Error> {
+        get_foolfuuka_posts(content, thread.id)
+    }
+
+    fn media_url(&self, thread: &Thread, file: &File) -> String {
+        let server_name = file.server_name();
+        format!(
+            "//img.4plebs.org/boards/{}/image/{}/{}/{}",
+            thread.board,
+            server_name.get(..4).unwrap_or_default(),
+            server_name.get(4..6).unwrap_or_default(),
+            server_name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::FilenameTemplate;
+
+    #[test]
+    fn it_gets_4plebs_links() {
+        let thread = Thread {
+            board: String::from("x"),
+            id:    32661196,
+        };
+        assert_eq!(
+            FourPlebs.api_url(&thread),
+            "https://archive.4plebs.org/_/api/chan/thread/?board=x&num=32661196"
+        );
+
+        let content = r#"{"32661196": {
+            "op": {"num": "32661196", "media": {
+                "media_orig": "1614942709612.jpg",
+                "media_filename": "ghost.jpg"
+            }},
+            "posts": {
+                "32661197": {"num": "32661197", "media": null},
+                "32661198": {"num": "32661198", "media": {
+                    "media_orig": "1660662319160984.png",
+                    "media_filename": "ufo.png"
+                }}
+            }
+        }}"#;
+        let links = FourPlebs
+            .get_links(&thread, content, &FilenameTemplate::original())
+            .unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[0].url,
+            "//img.4plebs.org/boards/x/image/1614/94/1614942709612.jpg"
+        );
+        assert_eq!(links[0].name, "ghost.jpg");
+        assert_eq!(
+            links[1].url,
+            "//img.4plebs.org/boards/x/image/1660/66/1660662319160984.png"
+        );
+        assert_eq!(links[1].name, "ufo.png");
+    }
+}
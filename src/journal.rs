@@ -0,0 +1,182 @@
+//! JSON lines files that only get appended to, used by the download state
+//! and the indexes
+
+use crate::Error;
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+/// A JSON lines file holding one entry per line.
+///
+/// New entries are appended, so the entries written before a crash survive
+/// it. Lines that can't be parsed, such as a line cut short by the crash, are
+/// skipped when the file is read.
+#[derive(Debug)]
+pub(crate) struct Journal<T> {
+    path:          PathBuf,
+    file:          Option<File>,
+    needs_newline: bool,
+    entries:       PhantomData<fn(T)>,
+}
+
+impl<T: Serialize + DeserializeOwned> Journal<T> {
+    /// Opens the file at `path`, returning its entries in order, or `None` if
+    /// it doesn't exist yet
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file exists but can't be read.
+    pub(crate) fn open(path: PathBuf) -> Result<(Self, Option<Vec<T>>), Error> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let journal = Self {
+                    path,
+                    file: None,
+                    needs_newline: false,
+                    entries: PhantomData,
+                };
+                return Ok((journal, None));
+            },
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Skipped line {} of {}: {}", number + 1, path.display(), err),
+            }
+        }
+        let journal = Self {
+            path,
+            file: None,
+            // A line cut short must not swallow the next entry
+            needs_newline: !content.is_empty() && !content.ends_with('\n'),
+            entries: PhantomData,
+        };
+        Ok((journal, Some(entries)))
+    }
+
+    /// Returns the path of the file
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry to the file, creating it if needed
+    ///
+    /// # Errors
+    ///
+    /// Fails if the entry can't be written.
+    pub(crate) fn append(&mut self, entry: &T) -> Result<(), Error> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(OpenOptions::new().create(true).append(true).open(&self.path)?),
+        };
+        let mut line = if self.needs_newline {
+            String::from("\n")
+        } else {
+            String::new()
+        };
+        line.push_str(&serde_json::to_string(entry)?);
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        self.needs_newline = false;
+        Ok(())
+    }
+
+    /// Replaces the content of the file with the entries
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be written.
+    pub(crate) fn rewrite<'a>(&mut self, entries: impl IntoIterator<Item = &'a T>) -> Result<(), Error>
+    where
+        T: 'a,
+    {
+        let temporary = self.path.with_extension("jsonl.tmp");
+        let mut content = String::new();
+        for entry in entries {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+        fs::write(&temporary, content)?;
+        self.file = None;
+        self.needs_newline = false;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Calls `visit` with every directory under `directory`, itself included,
+/// that holds a file accepted by `matches`.
+///
+/// The directories that can't be read are skipped with a warning, so that
+/// an index can still be built from the rest.
+///
+/// # Errors
+///
+/// Fails if `visit` fails.
+pub(crate) fn find_directories(
+    directory: &Path,
+    matches: &dyn Fn(&str) -> bool,
+    visit: &mut dyn FnMut(&Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Failed to index {}: {}", directory.display(), err);
+            return Ok(());
+        },
+    };
+    let mut found = false;
+    for entry in entries.flatten() {
+        if matches!(entry.file_type(), Ok(kind) if kind.is_dir()) {
+            find_directories(&entry.path(), matches, visit)?;
+        } else if !found && matches(&entry.file_name().to_string_lossy()) {
+            found = true;
+        }
+    }
+    if found {
+        visit(directory)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::empty_directory;
+
+    #[test]
+    fn it_appends_entries() {
+        let directory = empty_directory("chan-downloader-journal");
+        let path = directory.join("journal.jsonl");
+        let (mut journal, entries) = Journal::<u32>::open(path.clone()).unwrap();
+        assert_eq!(entries, None);
+        journal.append(&1).unwrap();
+        journal.append(&2).unwrap();
+        fs::write(&path, "1\n2\nbroken\n\n3").unwrap();
+
+        let (mut journal, entries) = Journal::<u32>::open(path.clone()).unwrap();
+        assert_eq!(entries, Some(vec![1, 2, 3]));
+        journal.append(&4).unwrap();
+        assert_eq!(
+            Journal::<u32>::open(path.clone()).unwrap().1,
+            Some(vec![1, 2, 3, 4])
+        );
+
+        journal.rewrite(&[5]).unwrap();
+        journal.append(&6).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "5\n6\n");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -4,28 +4,67 @@
 //! download images/webms from a 4chan thread
 
 use log::info;
-use reqwest::{Client, Error};
-use std::{
-    fs::File,
-    io::{self, Cursor},
+use md5::{Digest, Md5};
+use reqwest::{
+    header::{CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_RANGE, LAST_MODIFIED, RANGE},
+    Client,
+    Response,
+    StatusCode,
 };
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+pub mod catalog;
+pub mod config;
+pub mod dedup;
+pub mod error;
+pub mod filter;
+pub mod imageboard;
+mod journal;
+pub mod post;
+pub mod ratelimit;
+pub mod retry;
+pub mod schedule;
+pub mod state;
+pub mod template;
+pub mod verify;
+
+pub use error::{Error, ThreadUrlError};
+use imageboard::Registry;
+pub use post::{get_posts, Post};
 
 /// Represents a 4chan thread
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Thread {
     pub board: String,
     pub id:    u32,
 }
 
-#[derive(Debug)]
+/// Represents a file to download
+#[derive(Debug, Clone)]
 pub struct Link {
     pub url:  String,
     pub name: String,
+    /// Number of the post the file is attached to, when known
+    pub post: Option<u64>,
+    /// Metadata of the file given by the API, when known
+    pub file: Option<post::File>,
 }
 
 /// Saves the image from the url to the given path.
 /// Returns the path on success
 ///
+/// See [`download_file`] for how the image is written, and to check its MD5.
+///
+/// # Errors
+///
+/// Fails if the request fails, if the server doesn't answer with a success
+/// status, if the body is shorter than announced or if the file can't be
+/// written. Nothing is written to `path` on failure, but the partial file is
+/// kept to be resumed later.
+///
 /// # Examples
 ///
 /// ```
@@ -43,20 +82,217 @@ pub struct Link {
 /// };
 /// ```
 pub async fn save_image(url: &str, path: &str, client: &Client) -> Result<String, Error> {
+    let download = download_file(url, path, client, None).await?;
+    Ok(download.path)
+}
+
+/// File written by [`download_file`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Base64 encoded MD5 of the file, as given by the APIs
+    pub md5:  String,
+}
+
+/// Downloads the file from the url to the given path, checking its MD5
+/// against `expected_md5` when given.
+///
+/// The image is streamed to `path.part`, which is renamed to `path` once the
+/// whole body has been written and synced to the disk. A file found at `path`
+/// is therefore always complete.
+///
+/// When a previous attempt left a `path.part`, the download resumes where it
+/// stopped with a `Range` request. The `ETag` (or `Last-Modified`) of the first
+/// response is sent as `If-Range`, so the server sends the whole image again
+/// if it changed in the meantime or if it doesn't support ranges.
+///
+/// The file is hashed while it is written. When its MD5 doesn't match the
+/// expected one, the partial file is removed so the next attempt starts over.
+///
+/// # Errors
+///
+/// Fails if the request fails, if the server doesn't answer with a success
+/// status, if the body is shorter than announced, if the MD5 doesn't match
+/// ([`Error::Md5Mismatch`]) or if the file can't be written. Nothing is
+/// written to `path` on failure, but the partial file is kept to be resumed
+/// later, unless its MD5 is wrong.
+///
+/// # Examples
+///
+/// ```
+/// use reqwest::Client;
+/// use std::{env, fs::remove_file};
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// let workpath = env::current_dir().unwrap().join("1489266570954.jpg");
+/// let url = "https://i.4cdn.org/wg/1489266570954.jpg";
+/// async {
+///     let path = workpath.to_str().unwrap();
+///     let md5 = Some("Fb1y+3XGvZPxxFDPNLzvHA==");
+///     let download = chan_downloader::download_file(url, path, &client, md5)
+///         .await
+///         .unwrap();
+///     println!("Saved {} bytes to {}", download.size, download.path);
+///     remove_file(download.path).unwrap();
+/// };
+/// ```
+pub async fn download_file(
+    url: &str,
+    path: &str,
+    client: &Client,
+    expected_md5: Option<&str>,
+) -> Result<Download, Error> {
     info!(target: "image_events", "Saving image to: {}", path);
-    let response = client.get(url).send().await?;
+    let part_path = part_path(path);
+    let validator_path = format!("{}.validator", part_path);
+
+    let mut resume_from = resumable_length(&part_path, &validator_path).await;
+    let mut response = loop {
+        let mut request = client.get(url);
+        if let Some((offset, validator)) = &resume_from {
+            info!("Resuming {} from byte {}", path, offset);
+            request = request
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_RANGE, validator);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && resume_from.is_some() {
+            // The partial file doesn't match the image anymore
+            resume_from = None;
+            continue;
+        }
+        break response;
+    };
+    if !response.status().is_success() {
+        return Err(Error::from_response(url, &response));
+    }
+
+    let offset = match (&resume_from, response.status()) {
+        (Some((offset, _)), StatusCode::PARTIAL_CONTENT) => {
+            if content_range_start(&response) != Some(*offset) {
+                let _ = fs::remove_file(&validator_path).await;
+                return Err(Error::Status {
+                    url:    url.to_owned(),
+                    status: response.status(),
+                });
+            }
+            *offset
+        },
+        _ => {
+            let validator = [ETAG, LAST_MODIFIED]
+                .iter()
+                .find_map(|header| response.headers().get(header)?.to_str().ok());
+            match validator {
+                Some(validator) => fs::write(&validator_path, validator).await?,
+                None => {
+                    let _ = fs::remove_file(&validator_path).await;
+                },
+            }
+            0
+        },
+    };
 
-    if response.status().is_success() {
-        let mut dest = File::create(path).unwrap();
-        let mut content = Cursor::new(response.bytes().await?);
-        io::copy(&mut content, &mut dest).unwrap();
+    let expected = response.content_length().map(|length| offset + length);
+    let mut hasher = Md5::new();
+    let mut dest = if offset > 0 {
+        hash_file(&part_path, &mut hasher).await?;
+        fs::OpenOptions::new().append(true).open(&part_path).await?
+    } else {
+        fs::File::create(&part_path).await?
+    };
+    let mut received = offset;
+    while let Some(chunk) = response.chunk().await? {
+        dest.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        received += chunk.len() as u64;
     }
+    dest.sync_all().await?;
+    drop(dest);
+
+    if let Some(expected) = expected {
+        if received != expected {
+            return Err(Error::Incomplete {
+                url: url.to_owned(),
+                expected,
+                received,
+            });
+        }
+    }
+    let md5 = base64::encode(hasher.finalize());
+    if let Some(expected) = expected_md5 {
+        if md5 != expected {
+            let _ = fs::remove_file(&part_path).await;
+            let _ = fs::remove_file(&validator_path).await;
+            return Err(Error::Md5Mismatch {
+                url:      url.to_owned(),
+                expected: expected.to_owned(),
+                actual:   md5,
+            });
+        }
+    }
+    fs::rename(&part_path, path).await?;
+    let _ = fs::remove_file(&validator_path).await;
     info!("Saved image to: {}", path);
-    Ok(String::from(path))
+    Ok(Download {
+        path: String::from(path),
+        size: received,
+        md5,
+    })
+}
+
+/// Returns the base64 encoded MD5 of a file, as given by the APIs
+///
+/// # Errors
+///
+/// Fails if the file can't be read.
+pub async fn file_md5(path: &str) -> Result<String, Error> {
+    let mut hasher = Md5::new();
+    hash_file(path, &mut hasher).await?;
+    Ok(base64::encode(hasher.finalize()))
+}
+
+/// Feeds the content of a file to the hasher
+async fn hash_file(path: &str, hasher: &mut Md5) -> Result<(), Error> {
+    let mut file = fs::File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Returns the path an image is written to while it is downloaded
+#[must_use]
+pub fn part_path(path: &str) -> String {
+    format!("{}.part", path)
+}
+
+/// Returns the length of the partial file and the validator of its download,
+/// if it can be resumed
+async fn resumable_length(part_path: &str, validator_path: &str) -> Option<(u64, String)> {
+    let length = fs::metadata(part_path).await.ok()?.len();
+    let validator = fs::read_to_string(validator_path).await.ok()?;
+    (length > 0 && !validator.is_empty()).then_some((length, validator))
+}
+
+/// Returns the first byte of the `Content-Range` of the response
+fn content_range_start(response: &Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
 }
 
 /// Returns the page content from the given url.
 ///
+/// # Errors
+///
+/// Fails if the request fails or if the server doesn't answer with a
+/// success status. A missing page gives [`Error::NotFound`].
+///
 /// # Examples
 ///
 /// ```
@@ -74,35 +310,107 @@ pub async fn save_image(url: &str, path: &str, client: &Client) -> Result<String
 pub async fn get_page_content(url: &str, client: &Client) -> Result<String, Error> {
     info!(target: "page_events", "Loading page: {}", url);
     let response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::from_response(url, &response));
+    }
     let content = response.text().await?;
     info!("Loaded page: {}", url);
     Ok(content)
 }
 
+/// Content of a page requested with [`get_page_content_since`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum PageContent {
+    /// The page changed since the given date
+    Modified {
+        content:       String,
+        /// `Last-Modified` of the page, to send with the next request
+        last_modified: Option<String>,
+    },
+    /// The page didn't change since the given date
+    NotModified,
+}
+
+/// Returns the page content from the given url, if it changed since
+/// `last_modified`.
+///
+/// `last_modified` is sent as `If-Modified-Since`, so the server answers with
+/// an empty `304 Not Modified` when nothing changed, as the 4chan API does.
+///
+/// # Errors
+///
+/// Fails if the request fails or if the server doesn't answer with a
+/// success status. A missing page gives [`Error::NotFound`].
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{get_page_content_since, PageContent};
+/// use reqwest::Client;
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// let url = "https://a.4cdn.org/wg/thread/6872254.json";
+/// async {
+///     let last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT");
+///     match get_page_content_since(url, &client, last_modified)
+///         .await
+///         .unwrap()
+///     {
+///         PageContent::Modified { content, .. } => println!("{}", content),
+///         PageContent::NotModified => println!("No new posts"),
+///     }
+/// };
+/// ```
+pub async fn get_page_content_since(
+    url: &str,
+    client: &Client,
+    last_modified: Option<&str>,
+) -> Result<PageContent, Error> {
+    info!(target: "page_events", "Loading page: {} (modified since {:?})", url, last_modified);
+    let mut request = client.get(url);
+    if let Some(last_modified) = last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        info!("Page not modified: {}", url);
+        return Ok(PageContent::NotModified);
+    }
+    if !response.status().is_success() {
+        return Err(Error::from_response(url, &response));
+    }
+    let last_modified = response
+        .headers()
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let content = response.text().await?;
+    info!("Loaded page: {}", url);
+    Ok(PageContent::Modified { content, last_modified })
+}
+
 /// Returns the board name and thread id.
 ///
+/// Any url given by 4chan or 4plebs for a thread is accepted, with or without
+/// the scheme, a slug, an anchor or a query string.
+///
+/// # Errors
+///
+/// Fails if the url is not a 4chan or 4plebs thread url.
+///
 /// # Examples
 ///
 /// ```
-/// let url = "https://boards.4chan.org/wg/thread/6872254";
-/// let thread = chan_downloader::get_thread_info(url);
+/// let url = "https://boards.4chan.org/wg/thread/6872254/sticky#p6872300";
+/// let thread = chan_downloader::get_thread_info(url).unwrap();
 ///
 /// assert_eq!(thread.board, "wg");
 /// assert_eq!(thread.id, 6872254);
 /// ```
-#[must_use]
-pub fn get_thread_info(url: &str) -> Thread {
+pub fn get_thread_info(url: &str) -> Result<Thread, ThreadUrlError> {
     info!(target: "thread_events", "Getting thread info from: {}", url);
-    let url_vec: Vec<&str> = url.split('/').collect();
-    let board_name = url_vec[3];
-    let thread_vec: Vec<&str> = url_vec[5].split('#').collect();
-    let thread_id = thread_vec[0];
+    let (_, thread) = Registry::default().resolve(url)?;
     info!("Got thread info from: {}", url);
-
-    Thread {
-        board: board_name.to_owned(),
-        id:    thread_id.parse::<u32>().expect("failed to parse thread id"),
-    }
+    Ok(thread)
 }
 
 /// Returns the links and the number of links from a page.
@@ -146,11 +454,24 @@ pub fn get_image_links(page_content: &str) -> Vec<Link> {
         links_v.push(Link {
             url:  String::from(&cap[1]),
             name: String::from(&cap[2]),
+            post: None,
+            file: None,
         });
     }
     links_v
 }
 
+/// Replaces the characters that can't be used in a filename
+pub(crate) fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
 /// Initialize a [`Regex`] once
 #[macro_export]
 macro_rules! regex {
@@ -168,7 +489,7 @@ mod tests {
     #[test]
     fn it_gets_4chan_thread_info() {
         let url = "https://boards.4chan.org/wg/thread/6872254";
-        let thread = get_thread_info(url);
+        let thread = get_thread_info(url).unwrap();
         assert_eq!(thread.board, "wg");
         assert_eq!(thread.id, 6872254);
     }
@@ -176,11 +497,57 @@ mod tests {
     #[test]
     fn it_gets_4plebs_thread_info() {
         let url = "https://archive.4plebs.org/x/thread/32661196";
-        let thread = get_thread_info(url);
+        let thread = get_thread_info(url).unwrap();
         assert_eq!(thread.board, "x");
         assert_eq!(thread.id, 32661196);
     }
 
+    #[test]
+    fn it_gets_thread_info_from_every_url_shape() {
+        let urls = [
+            "https://boards.4chan.org/wg/thread/6872254",
+            "http://boards.4chan.org/wg/thread/6872254/",
+            "boards.4chan.org/wg/thread/6872254",
+            "//boards.4chan.org/wg/thread/6872254",
+            "https://boards.4chan.org/wg/thread/6872254/wallpaper-general",
+            "https://boards.4chan.org/wg/thread/6872254#p6872300",
+            "https://boards.4chan.org/wg/thread/6872254/wallpaper-general#bottom",
+            "https://boards.4chan.org/wg/thread/6872254?foo=bar",
+            "https://boards.4chan.org/wg/res/6872254.html",
+            "https://boards.4chan.org/wg/res/6872254.html#p6872300",
+            "https://boards.4channel.org/wg/thread/6872254",
+            "https://a.4cdn.org/wg/thread/6872254.json",
+            "https://4chan.org/wg/thread/6872254",
+            "https://archive.4plebs.org/wg/thread/6872254/",
+            "https://archive.4plebs.org/wg/thread/6872254/#6872300",
+            "https://archive.4plebs.org/_/api/chan/thread/?board=wg&num=6872254",
+            "  https://boards.4chan.org/wg/thread/6872254\n",
+        ];
+        for url in urls {
+            let thread = get_thread_info(url).unwrap_or_else(|e| panic!("{}: {}", url, e));
+            assert_eq!(thread.board, "wg", "{}", url);
+            assert_eq!(thread.id, 6872254, "{}", url);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_thread_urls() {
+        let urls = [
+            "",
+            "https://boards.4chan.org/wg/",
+            "https://boards.4chan.org/wg/catalog",
+            "https://boards.4chan.org/wg/thread/",
+            "https://boards.4chan.org/wg/thread/abc",
+            "https://boards.4chan.org/wg/thread/-1",
+            "https://boards.4chan.org/wg/thread/99999999999",
+            "https://archive.4plebs.org/_/api/chan/thread/?board=x",
+            "https://example.org/wg/thread/6872254",
+        ];
+        for url in urls {
+            assert!(get_thread_info(url).is_err(), "{}", url);
+        }
+    }
+
     #[test]
     fn it_gets_4chan_image_links() {
         let links_iter = get_image_links(
@@ -204,7 +571,10 @@ mod tests {
         "#,
         );
         for link in links_iter {
-            assert_eq!(link.url, "//img.4plebs.org/boards/x/image/1660/66/1660662319160984.png");
+            assert_eq!(
+                link.url,
+                "//img.4plebs.org/boards/x/image/1660/66/1660662319160984.png"
+            );
             assert_eq!(link.name, "1660662319160984.png");
         }
     }
@@ -242,4 +612,215 @@ mod tests {
         assert_eq!(workpath.to_str().unwrap(), answer);
         fs::remove_file(answer).unwrap();
     }
+
+    #[tokio::test]
+    async fn it_fails_to_save_missing_image() {
+        use std::fs;
+        let (url, _) = serve(vec!["HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"]).await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-missing");
+        let workpath = directory.join("image.jpg");
+        let result = save_image(&url, workpath.to_str().unwrap(), &client).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert!(!workpath.exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_streams_image_to_disk() {
+        use std::fs;
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage"]).await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-streamed");
+        let workpath = directory.join("image.jpg");
+        let path = workpath.to_str().unwrap();
+        save_image(&url, path, &client).await.unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"image");
+        assert!(!std::path::Path::new(&part_path(path)).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_fails_to_save_truncated_image() {
+        use std::fs;
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nimage"]).await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-truncated");
+        let workpath = directory.join("image.jpg");
+        let result = save_image(&url, workpath.to_str().unwrap(), &client).await;
+        assert!(result.is_err());
+        assert!(!workpath.exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_resumes_partial_image() {
+        use std::fs;
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 3-4/5\r\nContent-Length: 2\r\n\r\nge",
+        ])
+        .await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-resumed");
+        let workpath = directory.join("image.jpg");
+        let path = workpath.to_str().unwrap();
+        fs::write(part_path(path), "ima").unwrap();
+        fs::write(format!("{}.validator", part_path(path)), "\"abc\"").unwrap();
+
+        save_image(&url, path, &client).await.unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"image");
+        let request = requests.lock().unwrap()[0].to_lowercase();
+        assert!(request.contains("range: bytes=3-"));
+        assert!(request.contains("if-range: \"abc\""));
+        assert!(!std::path::Path::new(&format!("{}.validator", part_path(path))).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_restarts_image_when_range_is_ignored() {
+        use std::fs;
+        let (url, _) = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage"]).await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-restarted");
+        let workpath = directory.join("image.jpg");
+        let path = workpath.to_str().unwrap();
+        fs::write(part_path(path), "old").unwrap();
+        fs::write(format!("{}.validator", part_path(path)), "\"abc\"").unwrap();
+
+        save_image(&url, path, &client).await.unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"image");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_checks_the_md5_of_downloads() {
+        use std::fs;
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage",
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage",
+        ])
+        .await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-md5");
+        let workpath = directory.join("image.jpg");
+        let path = workpath.to_str().unwrap();
+        let md5 = "eIBaIhqYjnnvP0LXxb/UGA==";
+
+        let download = download_file(&url, path, &client, Some(md5)).await.unwrap();
+        assert_eq!(download, Download {
+            path: String::from(path),
+            size: 5,
+            md5:  String::from(md5),
+        });
+        assert_eq!(file_md5(path).await.unwrap(), md5);
+        fs::remove_file(path).unwrap();
+
+        let result = download_file(&url, path, &client, Some("Fb1y+3XGvZPxxFDPNLzvHA==")).await;
+        assert!(matches!(result, Err(Error::Md5Mismatch { actual, .. }) if actual == md5));
+        assert!(!workpath.exists());
+        assert!(!std::path::Path::new(&part_path(path)).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_checks_the_md5_of_resumed_downloads() {
+        use std::fs;
+        let (url, _) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 3-4/5\r\nContent-Length: 2\r\n\r\nge",
+        ])
+        .await;
+        let client = Client::new();
+        let directory = empty_directory("chan-downloader-md5-resumed");
+        let workpath = directory.join("image.jpg");
+        let path = workpath.to_str().unwrap();
+        fs::write(part_path(path), "ima").unwrap();
+        fs::write(format!("{}.validator", part_path(path)), "\"abc\"").unwrap();
+
+        let download = download_file(&url, path, &client, Some("eIBaIhqYjnnvP0LXxb/UGA=="))
+            .await
+            .unwrap();
+        assert_eq!(download.size, 5);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_gets_page_content_since() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 200 OK\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\nContent-Length: \
+             2\r\n\r\n{}",
+            "HTTP/1.1 304 Not Modified\r\n\r\n",
+        ])
+        .await;
+        let client = Client::new();
+
+        let page = get_page_content_since(&url, &client, None).await.unwrap();
+        assert_eq!(page, PageContent::Modified {
+            content:       String::from("{}"),
+            last_modified: Some(String::from(date)),
+        });
+        let page = get_page_content_since(&url, &client, Some(date)).await.unwrap();
+        assert_eq!(page, PageContent::NotModified);
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].to_lowercase().contains("if-modified-since"));
+        assert!(requests[1]
+            .to_lowercase()
+            .contains(&format!("if-modified-since: {}", date).to_lowercase()));
+    }
+
+    /// Creates a new empty directory in the temporary directory, unique to
+    /// the test and to the process so that tests can run concurrently
+    pub(crate) fn empty_directory(name: &str) -> std::path::PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let directory = std::env::temp_dir().join(format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Answers the requests made to the returned url with the given raw
+    /// responses, in order. The received requests are recorded.
+    pub(crate) async fn serve(
+        responses: Vec<&'static str>,
+    ) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::sync::{Arc, Mutex};
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file.jpg", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request).into_owned());
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
 }
@@ -0,0 +1,441 @@
+//! Typed posts parsed from the thread APIs
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Represents a post of a thread
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Post {
+    /// Post number
+    pub no:            u64,
+    /// UNIX timestamp of the post
+    pub time:          i64,
+    pub name:          Option<String>,
+    pub tripcode:      Option<String>,
+    /// Poster ID, only given on boards that show them
+    pub id:            Option<String>,
+    pub subject:       Option<String>,
+    /// Comment, as HTML for 4chan and as plain text for FoolFuuka archives
+    pub comment:       Option<String>,
+    /// Set when the comment is plain text rather than HTML
+    #[serde(default)]
+    pub plain_comment: bool,
+    pub file:          Option<File>,
+    /// Set on the OP when the thread has been archived
+    #[serde(default)]
+    pub archived:      bool,
+    /// Set on the OP when the thread has been closed
+    #[serde(default)]
+    pub closed:        bool,
+    /// Set on the OP when the thread reached the bump limit
+    #[serde(default)]
+    pub bump_limit:    bool,
+}
+
+impl Post {
+    /// Returns the comment as plain text, without the HTML tags and entities
+    /// of the 4chan comments. The plain text comments of the FoolFuuka
+    /// archives are returned as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chan_downloader::Post;
+    /// let post: Post = serde_json::from_str(
+    ///     r#"{"no": 1, "time": 0, "name": null, "tripcode": null, "id": null, "subject": null,
+    ///         "comment": "<span class=\"quote\">&gt;previous thread</span><br>New &amp; improved",
+    ///         "file": null}"#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     post.comment_text().unwrap(),
+    ///     ">previous thread\nNew & improved"
+    /// );
+    /// ```
+    #[must_use]
+    pub fn comment_text(&self) -> Option<String> {
+        let comment = self.comment.as_ref()?;
+        if self.plain_comment {
+            return Some(comment.clone());
+        }
+        let mut text = String::with_capacity(comment.len());
+        let mut rest = comment.as_str();
+        while let Some(start) = rest.find('<') {
+            text.push_str(&rest[..start]);
+            let end = rest[start..].find('>').map_or(rest.len(), |end| start + end + 1);
+            if rest[start..end].starts_with("<br") {
+                text.push('\n');
+            }
+            rest = &rest[end..];
+        }
+        text.push_str(rest);
+        Some(decode_entities(&text))
+    }
+
+    /// Returns the subject without the HTML entities of the 4chan subjects
+    #[must_use]
+    pub fn subject_text(&self) -> Option<String> {
+        self.subject.as_deref().map(decode_entities)
+    }
+}
+
+/// Decodes the HTML entities used by 4chan
+fn decode_entities(text: &str) -> String {
+    text.replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&quot;", "\"")
+        .replace("&#039;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Represents the file attached to a post
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct File {
+    /// UNIX timestamp with microseconds, used as the name on the server
+    pub tim:      u64,
+    /// Filename given by the poster, without the extension
+    pub filename: String,
+    /// Extension with the leading dot
+    pub ext:      String,
+    /// Size in bytes
+    pub fsize:    u64,
+    /// Base64 encoded MD5 of the file
+    pub md5:      String,
+    pub w:        u32,
+    pub h:        u32,
+    pub tn_w:     u32,
+    pub tn_h:     u32,
+    pub spoiler:  bool,
+    pub deleted:  bool,
+}
+
+impl File {
+    /// Returns the name of the file on the server
+    #[must_use]
+    pub fn server_name(&self) -> String {
+        format!("{}{}", self.tim, self.ext)
+    }
+
+    /// Returns the filename given by the poster, with the extension
+    #[must_use]
+    pub fn original_name(&self) -> String {
+        format!("{}{}", self.filename, self.ext)
+    }
+}
+
+#[derive(Deserialize)]
+struct ChanThread {
+    posts: Vec<ChanPost>,
+}
+
+#[derive(Deserialize)]
+struct ChanPost {
+    no:          u64,
+    #[serde(default)]
+    time:        i64,
+    name:        Option<String>,
+    trip:        Option<String>,
+    id:          Option<String>,
+    sub:         Option<String>,
+    com:         Option<String>,
+    tim:         Option<u64>,
+    filename:    Option<String>,
+    ext:         Option<String>,
+    #[serde(default)]
+    fsize:       u64,
+    #[serde(default)]
+    md5:         String,
+    #[serde(default)]
+    w:           u32,
+    #[serde(default)]
+    h:           u32,
+    #[serde(default)]
+    tn_w:        u32,
+    #[serde(default)]
+    tn_h:        u32,
+    #[serde(default)]
+    spoiler:     u8,
+    #[serde(default)]
+    filedeleted: u8,
+    #[serde(default)]
+    archived:    u8,
+    #[serde(default)]
+    closed:      u8,
+    #[serde(default)]
+    bumplimit:   u8,
+}
+
+impl From<ChanPost> for Post {
+    fn from(post: ChanPost) -> Self {
+        let file = match (post.tim, post.ext) {
+            (Some(tim), Some(ext)) => Some(File {
+                tim,
+                filename: post.filename.unwrap_or_default(),
+                ext,
+                fsize: post.fsize,
+                md5: post.md5,
+                w: post.w,
+                h: post.h,
+                tn_w: post.tn_w,
+                tn_h: post.tn_h,
+                spoiler: post.spoiler == 1,
+                deleted: post.filedeleted == 1,
+            }),
+            _ => None,
+        };
+        Self {
+            no: post.no,
+            time: post.time,
+            name: post.name,
+            tripcode: post.trip,
+            id: post.id,
+            subject: post.sub,
+            comment: post.com,
+            plain_comment: false,
+            file,
+            archived: post.archived == 1,
+            closed: post.closed == 1,
+            bump_limit: post.bumplimit == 1,
+        }
+    }
+}
+
+/// Returns the posts of a thread from the 4chan API content.
+///
+/// # Examples
+///
+/// ```
+/// let content = r#"{"posts": [{"no": 570368, "time": 1489266570, "sub": "Papercraft",
+///     "tim": 1489266570954, "filename": "stickyop", "ext": ".jpg", "fsize": 1024,
+///     "md5": "Fb1y+3XGvZPxxFDPNLzvHA==", "w": 800, "h": 600}]}"#;
+/// let posts = chan_downloader::get_posts(content).unwrap();
+///
+/// assert_eq!(posts[0].no, 570368);
+/// assert_eq!(posts[0].subject.as_deref(), Some("Papercraft"));
+/// assert_eq!(
+///     posts[0].file.as_ref().unwrap().original_name(),
+///     "stickyop.jpg"
+/// );
+/// ```
+pub fn get_posts(thread_json: &str) -> Result<Vec<Post>, serde_json::Error> {
+    let thread: ChanThread = serde_json::from_str(thread_json)?;
+    Ok(thread.posts.into_iter().map(Post::from).collect())
+}
+
+#[derive(Deserialize)]
+struct ChanCatalogPage {
+    threads: Vec<ChanPost>,
+}
+
+/// Returns the OPs of the threads of a board from the 4chan catalog API
+/// content, in the order of the catalog.
+///
+/// # Examples
+///
+/// ```
+/// let content = r#"[{"page": 1, "threads": [{"no": 7970152, "time": 1661019073,
+///     "sub": "Wallpaper General"}]}]"#;
+/// let threads = chan_downloader::post::get_catalog_posts(content).unwrap();
+///
+/// assert_eq!(threads[0].no, 7970152);
+/// assert_eq!(threads[0].subject.as_deref(), Some("Wallpaper General"));
+/// ```
+pub fn get_catalog_posts(catalog_json: &str) -> Result<Vec<Post>, serde_json::Error> {
+    let pages: Vec<ChanCatalogPage> = serde_json::from_str(catalog_json)?;
+    Ok(pages
+        .into_iter()
+        .flat_map(|page| page.threads)
+        .map(Post::from)
+        .collect())
+}
+
+/// Returns the posts of a thread from the FoolFuuka API content, as used by
+/// 4plebs.
+///
+/// The API answers with an object keyed by the thread number, holding the OP
+/// and the replies keyed by their post number.
+pub(crate) fn get_foolfuuka_posts(
+    thread_json: &str,
+    thread_id: u32,
+) -> Result<Vec<Post>, serde_json::Error> {
+    let json: Value = serde_json::from_str(thread_json)?;
+    let thread = &json[thread_id.to_string()];
+    let replies = thread["posts"].as_object().into_iter().flat_map(|p| p.values());
+
+    let mut posts: Vec<Post> = std::iter::once(&thread["op"])
+        .chain(replies)
+        .filter(|post| post.is_object())
+        .map(foolfuuka_post)
+        .collect();
+    posts.sort_by_key(|post| post.no);
+    Ok(posts)
+}
+
+fn foolfuuka_post(post: &Value) -> Post {
+    let media = &post["media"];
+    let file = media["media_orig"].as_str().map(|server_name| {
+        let (tim, ext) = server_name.split_at(server_name.find('.').unwrap_or(server_name.len()));
+        let original = media["media_filename"].as_str().unwrap_or(server_name);
+        let filename = original.strip_suffix(ext).unwrap_or(original);
+        File {
+            tim:      tim.parse().unwrap_or_default(),
+            filename: filename.to_owned(),
+            ext:      ext.to_owned(),
+            fsize:    lenient_u64(&media["media_size"]),
+            md5:      media["media_hash"].as_str().unwrap_or_default().to_owned(),
+            w:        lenient_u64(&media["media_w"]) as u32,
+            h:        lenient_u64(&media["media_h"]) as u32,
+            tn_w:     lenient_u64(&media["preview_w"]) as u32,
+            tn_h:     lenient_u64(&media["preview_h"]) as u32,
+            spoiler:  lenient_u64(&media["spoiler"]) == 1,
+            deleted:  lenient_u64(&media["banned"]) == 1,
+        }
+    });
+
+    Post {
+        no: lenient_u64(&post["num"]),
+        time: lenient_u64(&post["timestamp"]) as i64,
+        name: lenient_string(&post["name"]),
+        tripcode: lenient_string(&post["trip"]),
+        id: lenient_string(&post["poster_hash"]),
+        subject: lenient_string(&post["title"]),
+        comment: lenient_string(&post["comment"]),
+        plain_comment: true,
+        file,
+        // The archive keeps a thread after it died on 4chan
+        archived: lenient_u64(&post["timestamp_expired"]) != 0,
+        closed: lenient_u64(&post["locked"]) == 1,
+        bump_limit: false,
+    }
+}
+
+/// FoolFuuka sends most of its numbers as strings
+fn lenient_u64(value: &Value) -> u64 {
+    match value {
+        Value::Number(n) => n.as_u64().unwrap_or_default(),
+        Value::String(s) => s.parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+fn lenient_string(value: &Value) -> Option<String> {
+    value.as_str().filter(|s| !s.is_empty()).map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_gets_4chan_posts() {
+        let content = r#"{"posts": [
+            {"no": 570368, "time": 1489266570, "name": "Anonymous", "trip": "!Ep8pui8Vw2",
+             "id": "Bg7rXm9r", "sub": "Papercraft", "com": "Post your <b>models</b>",
+             "tim": 1489266570954, "filename": "stickyop", "ext": ".jpg", "fsize": 2048,
+             "md5": "Fb1y+3XGvZPxxFDPNLzvHA==", "w": 1920, "h": 1080, "tn_w": 250,
+             "tn_h": 140, "spoiler": 1},
+            {"no": 570369, "time": 1489266600, "name": "Anonymous", "com": "bump"},
+            {"no": 570370, "time": 1489266700, "tim": 1489266700001, "filename": "gone",
+             "ext": ".png", "filedeleted": 1}
+        ]}"#;
+        let posts = get_posts(content).unwrap();
+        assert_eq!(posts.len(), 3);
+
+        let op = &posts[0];
+        assert_eq!(op.tripcode.as_deref(), Some("!Ep8pui8Vw2"));
+        assert_eq!(op.id.as_deref(), Some("Bg7rXm9r"));
+        assert_eq!(
+            op.file,
+            Some(File {
+                tim:      1489266570954,
+                filename: String::from("stickyop"),
+                ext:      String::from(".jpg"),
+                fsize:    2048,
+                md5:      String::from("Fb1y+3XGvZPxxFDPNLzvHA=="),
+                w:        1920,
+                h:        1080,
+                tn_w:     250,
+                tn_h:     140,
+                spoiler:  true,
+                deleted:  false,
+            })
+        );
+
+        assert!(!op.archived);
+        assert_eq!(posts[1].file, None);
+        assert!(posts[2].file.as_ref().unwrap().deleted);
+    }
+
+    #[test]
+    fn it_gets_foolfuuka_posts() {
+        let content = r#"{"32661196": {
+            "op": {"num": "32661196", "timestamp": 1614942709, "name": "Anonymous",
+                   "trip": null, "poster_hash": "", "title": "Ghosts", "comment": "I <3 ghosts",
+                   "media": {"media_orig": "1614942709612.jpg", "media_filename": "ghost.jpg",
+                             "media_size": "1234", "media_hash": "Fb1y+3XGvZPxxFDPNLzvHA==",
+                             "media_w": "800", "media_h": "600", "preview_w": "250",
+                             "preview_h": "187", "spoiler": "0", "banned": "0"}},
+            "posts": {"32661198": {"num": "32661198", "timestamp": 1614942800, "media": null},
+                      "32661197": {"num": "32661197", "timestamp": 1614942750, "media": null}}
+        }}"#;
+        let posts = get_foolfuuka_posts(content, 32661196).unwrap();
+        assert_eq!(posts.iter().map(|p| p.no).collect::<Vec<_>>(), [
+            32661196, 32661197, 32661198
+        ]);
+
+        let op = &posts[0];
+        assert_eq!(op.subject.as_deref(), Some("Ghosts"));
+        assert_eq!(op.comment_text().as_deref(), Some("I <3 ghosts"));
+        assert_eq!(op.id, None);
+        let file = op.file.as_ref().unwrap();
+        assert_eq!(file.tim, 1614942709612);
+        assert_eq!(file.filename, "ghost");
+        assert_eq!(file.ext, ".jpg");
+        assert_eq!(file.fsize, 1234);
+        assert_eq!((file.w, file.h), (800, 600));
+    }
+
+    #[test]
+    fn it_gets_catalog_posts() {
+        let content = r#"[
+            {"page": 1, "threads": [{"no": 1, "time": 10, "sub": "Wallpaper General", "replies": 5},
+                                    {"no": 2, "time": 20, "com": "bump", "last_replies": []}]},
+            {"page": 2, "threads": [{"no": 3, "time": 30}]}
+        ]"#;
+        let posts = get_catalog_posts(content).unwrap();
+        assert_eq!(posts.iter().map(|p| p.no).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(posts[0].subject.as_deref(), Some("Wallpaper General"));
+    }
+
+    #[test]
+    fn it_gets_comment_text() {
+        let mut post = get_posts(r#"{"posts": [{"no": 1}]}"#).unwrap().remove(0);
+        assert_eq!(post.comment_text(), None);
+        post.comment = Some(String::from(
+            "<a href=\"#p1\" class=\"quotelink\">&gt;&gt;1</a><br><br>Tom &amp; Jerry&#039;s \
+             <b>&quot;wallpapers&quot;</b> &lt;3",
+        ));
+        assert_eq!(
+            post.comment_text().unwrap(),
+            ">>1\n\nTom & Jerry's \"wallpapers\" <3"
+        );
+
+        post.comment = Some(String::from("I <3 this &amp; that"));
+        post.plain_comment = true;
+        assert_eq!(post.comment_text().unwrap(), "I <3 this &amp; that");
+    }
+
+    #[test]
+    fn it_gets_dead_threads() {
+        let posts =
+            get_posts(r#"{"posts": [{"no": 1, "archived": 1, "closed": 1, "bumplimit": 1}]}"#).unwrap();
+        assert!(posts[0].archived);
+        assert!(posts[0].closed);
+        assert!(posts[0].bump_limit);
+
+        let content = r#"{"1": {"op": {"num": "1", "timestamp_expired": "1614942709", "locked": "0"}}}"#;
+        let posts = get_foolfuuka_posts(content, 1).unwrap();
+        assert!(posts[0].archived);
+        assert!(!posts[0].closed);
+    }
+}
@@ -0,0 +1,207 @@
+//! Per-host request rate limiting
+
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Token bucket limiting the rate of the requests sent to each host.
+///
+/// The [4chan API rules](https://github.com/4chan/4chan-API#api-rules) ask
+/// for no more than one request per second, which is the default. The CDNs
+/// serving the files allow more, so downloads can use a second limiter with
+/// a higher rate. Clones of a limiter share the same buckets, so a limiter
+/// can be shared by every task using the same [`Client`](reqwest::Client).
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{get_page_content, ratelimit::RateLimiter};
+/// use reqwest::Client;
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// let limiter = RateLimiter::default();
+/// let url = "https://a.4cdn.org/wg/thread/6872254.json";
+/// async {
+///     limiter.acquire(url).await;
+///     let content = get_page_content(url, &client).await.unwrap();
+///     println!("{}", content);
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate:    f64,
+    burst:   f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens left, negative when requests are waiting for their turn
+    tokens:      f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Returns a limiter allowing `requests_per_second` requests to each host,
+    /// and up to `burst` requests at once after a pause.
+    /// A rate of 0 doesn't limit anything.
+    #[must_use]
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            rate:    requests_per_second,
+            burst:   f64::from(burst.max(1)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Waits until a request can be sent to the host of the url
+    pub async fn acquire(&self, url: &str) {
+        if let Some(wait) = self.reserve(url, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes a token from the bucket of the host, returning how long to wait
+    /// for it
+    fn reserve(&self, url: &str, now: Instant) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(ToOwned::to_owned))
+            .unwrap_or_default();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets.entry(host).or_insert(Bucket {
+            tokens:      self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        bucket.last_refill = now;
+
+        // A tiny rate makes the wait too long for a `Duration`
+        (bucket.tokens < 0.0)
+            .then(|| Duration::try_from_secs_f64(-bucket.tokens / self.rate).unwrap_or(Duration::MAX))
+    }
+}
+
+/// Checks a rate in requests per second, which must be finite and positive,
+/// or 0 to disable the limit
+///
+/// # Errors
+///
+/// Fails if the rate is negative, infinite or NaN.
+pub fn check_rate(rate: f64) -> Result<f64, String> {
+    if rate.is_finite() && rate >= 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "invalid rate '{}', expected a positive number of requests per second",
+            rate
+        ))
+    }
+}
+
+/// Parses a rate in requests per second, such as `0.5`
+///
+/// # Errors
+///
+/// Fails if the value is not a number, or if [`check_rate`] fails.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::ratelimit::parse_rate;
+/// assert_eq!(parse_rate("0.5"), Ok(0.5));
+/// assert!(parse_rate("-1").is_err());
+/// ```
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .map_err(|err| format!("invalid rate '{}': {}", value, err))
+        .and_then(check_rate)
+}
+
+impl Default for RateLimiter {
+    /// Allows one request per second to each host
+    fn default() -> Self {
+        Self::new(1.0, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_limits_each_host() {
+        let limiter = RateLimiter::new(2.0, 1);
+        let now = Instant::now();
+        let api = "https://a.4cdn.org/wg/thread/6872254.json";
+        let image = "https://i.4cdn.org/wg/1489266570954.jpg";
+
+        assert_eq!(limiter.reserve(api, now), None);
+        assert_eq!(limiter.reserve(image, now), None);
+        assert_eq!(limiter.reserve(api, now), Some(Duration::from_millis(500)));
+        assert_eq!(limiter.reserve(api, now), Some(Duration::from_secs(1)));
+        assert_eq!(limiter.reserve(image, now + Duration::from_millis(500)), None);
+    }
+
+    #[test]
+    fn it_allows_bursts() {
+        let limiter = RateLimiter::new(1.0, 3);
+        let now = Instant::now();
+        let url = "https://i.4cdn.org/wg/1489266570954.jpg";
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(url, now), None);
+        }
+        assert_eq!(limiter.reserve(url, now), Some(Duration::from_secs(1)));
+        // The bucket never holds more than the burst
+        let later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(url, later), None);
+        }
+        assert!(limiter.reserve(url, later).is_some());
+    }
+
+    #[test]
+    fn it_shares_buckets_between_clones() {
+        let limiter = RateLimiter::default();
+        let clone = limiter.clone();
+        let now = Instant::now();
+        let url = "https://a.4cdn.org/wg/thread/6872254.json";
+        assert_eq!(limiter.reserve(url, now), None);
+        assert_eq!(clone.reserve(url, now), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn it_saturates_tiny_rates() {
+        let limiter = RateLimiter::new(1e-30, 1);
+        let now = Instant::now();
+        let url = "https://a.4cdn.org/wg/thread/6872254.json";
+        assert_eq!(limiter.reserve(url, now), None);
+        assert_eq!(limiter.reserve(url, now), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn it_parses_rates() {
+        assert_eq!(parse_rate("2"), Ok(2.0));
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        for invalid in ["-1", "NaN", "inf", "fast", ""] {
+            assert!(parse_rate(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_can_be_unlimited() {
+        let limiter = RateLimiter::new(0.0, 1);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve("https://a.4cdn.org/", now), None);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Retries of failed requests
+
+use crate::Error;
+use log::warn;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Longest `Retry-After` honored before giving up on a request
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Policy used to retry the requests failing with a transient error.
+///
+/// Retries wait for an exponential backoff with full jitter, capped by the
+/// maximum delay, or for the `Retry-After` asked by the server, up to
+/// [`MAX_RETRY_AFTER`]. Clones of a policy share the same retry
+/// budget, which caps the number of retries of a whole run.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{get_page_content, retry::RetryPolicy};
+/// use reqwest::Client;
+/// let client = Client::builder().user_agent("reqwest").build().unwrap();
+/// let policy = RetryPolicy::default();
+/// let url = "https://a.4cdn.org/wg/thread/6872254.json";
+/// async {
+///     let content = policy.run(|| get_page_content(url, &client)).await.unwrap();
+///     println!("{}", content);
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay:  Duration,
+    max_delay:   Duration,
+    budget:      Arc<AtomicU32>,
+}
+
+impl RetryPolicy {
+    /// Returns a policy retrying each request up to `max_retries` times,
+    /// and all the requests up to `budget` times in total
+    #[must_use]
+    pub fn new(max_retries: u32, base_delay: Duration, max_delay: Duration, budget: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+            max_delay,
+            budget: Arc::new(AtomicU32::new(budget)),
+        }
+    }
+
+    /// Returns a policy that never retries
+    #[must_use]
+    pub fn none() -> Self {
+        Self::new(0, Duration::ZERO, Duration::ZERO, 0)
+    }
+
+    /// Returns the number of retries left in the budget
+    #[must_use]
+    pub fn remaining_budget(&self) -> u32 {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    /// Runs the request made by `f`, retrying it while it fails with a
+    /// transient error.
+    ///
+    /// # Errors
+    ///
+    /// Returns the last error once the request fails with a permanent error,
+    /// runs out of retries, or is asked to wait longer than
+    /// [`MAX_RETRY_AFTER`].
+    pub async fn run<T, F, Fut>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let err = match f().await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if !err.is_transient() || attempt >= self.max_retries {
+                return Err(err);
+            }
+            let delay = match self.delay(attempt, &err) {
+                Some(delay) => delay,
+                None => {
+                    warn!(
+                        "{}; the server asked to wait longer than {:?}, giving up",
+                        err, MAX_RETRY_AFTER
+                    );
+                    return Err(err);
+                },
+            };
+            if !self.take_budget() {
+                return Err(err);
+            }
+            attempt += 1;
+            warn!("{}; retry {}/{} in {:?}", err, attempt, self.max_retries, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Returns the delay before the next attempt, or `None` if the server
+    /// asked to wait longer than [`MAX_RETRY_AFTER`]
+    fn delay(&self, attempt: u32, err: &Error) -> Option<Duration> {
+        match err.retry_after() {
+            Some(retry_after) => (retry_after <= MAX_RETRY_AFTER).then_some(retry_after),
+            None => {
+                let backoff = self
+                    .base_delay
+                    .saturating_mul(2_u32.saturating_pow(attempt))
+                    .min(self.max_delay);
+                Some(backoff.mul_f64(fastrand::f64()))
+            },
+        }
+    }
+
+    fn take_budget(&self) -> bool {
+        self.budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+                budget.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Retries each request up to 3 times, waiting from 1 second up to
+    /// 1 minute, and 100 times in total
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(1), Duration::from_secs(60), 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::cell::Cell;
+
+    fn unavailable(retry_after: Option<Duration>) -> Error {
+        Error::from_status(
+            "https://i.4cdn.org/wg/1.jpg",
+            StatusCode::SERVICE_UNAVAILABLE,
+            retry_after,
+        )
+    }
+
+    #[tokio::test]
+    async fn it_retries_transient_errors() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(10), 10);
+        let attempts = Cell::new(0);
+        let result = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    if attempt < 3 {
+                        Err(unavailable(None))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(policy.remaining_budget(), 8);
+    }
+
+    #[tokio::test]
+    async fn it_gives_up() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_millis(10), 10);
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(unavailable(None)) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let result: Result<(), _> = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(Error::NotFound(String::from("https://i.4cdn.org/wg/1.jpg"))) }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_budget() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(1), Duration::from_millis(10), 2);
+        let clone = policy.clone();
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = clone
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(unavailable(None)) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(policy.remaining_budget(), 0);
+    }
+
+    #[test]
+    fn it_honors_retry_after() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1), Duration::from_secs(60), 10);
+        let delay = policy.delay(0, &unavailable(Some(Duration::from_secs(30))));
+        assert_eq!(delay, Some(Duration::from_secs(30)));
+        // The maximum delay only caps the backoff
+        let delay = policy.delay(0, &unavailable(Some(Duration::from_secs(600))));
+        assert_eq!(delay, Some(Duration::from_secs(600)));
+        assert_eq!(
+            policy.delay(0, &unavailable(Some(MAX_RETRY_AFTER + Duration::from_secs(1)))),
+            None
+        );
+        for attempt in 0..10 {
+            let delay = policy.delay(attempt, &unavailable(None)).unwrap();
+            assert!(delay <= Duration::from_secs(60));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Scheduling of thread reloads
+
+use std::time::Duration;
+
+/// Interval between the reloads of a thread.
+///
+/// Like the thread updater of 4chan X, the interval goes back to its minimum
+/// as soon as new posts show up, and doubles after every reload without new
+/// posts, up to its maximum.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::schedule::AdaptiveInterval;
+/// use std::time::Duration;
+///
+/// let mut interval = AdaptiveInterval::new(Duration::from_secs(30), Duration::from_secs(300));
+/// assert_eq!(interval.next(false), Duration::from_secs(60));
+/// assert_eq!(interval.next(false), Duration::from_secs(120));
+/// assert_eq!(interval.next(true), Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct AdaptiveInterval {
+    min:     Duration,
+    max:     Duration,
+    current: Duration,
+}
+
+impl AdaptiveInterval {
+    /// Returns an interval starting at `min`, and never going over `max`
+    #[must_use]
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let max = max.max(min);
+        Self { min, max, current: min }
+    }
+
+    /// Returns an interval that never changes
+    #[must_use]
+    pub fn fixed(interval: Duration) -> Self {
+        Self::new(interval, interval)
+    }
+
+    /// Returns the current interval
+    #[must_use]
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Returns the interval to wait after a reload, depending on whether the
+    /// reload found new posts
+    pub fn next(&mut self, new_posts: bool) -> Duration {
+        self.current = if new_posts {
+            self.min
+        } else {
+            self.current.saturating_mul(2).clamp(self.min, self.max)
+        };
+        self.current
+    }
+}
+
+/// Parses a duration such as `30s`, `5m`, `1h` or `1m30s`.
+/// A number without unit is a number of minutes.
+///
+/// # Errors
+///
+/// Fails if the duration is empty, uses an unknown unit, or is too long to
+/// be represented.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::schedule::parse_duration;
+/// use std::time::Duration;
+///
+/// assert_eq!(parse_duration("5"), Ok(Duration::from_secs(300)));
+/// assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+/// ```
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Ok(minutes) = value.parse::<u64>() {
+        return from_minutes(minutes);
+    }
+    let too_long = || format!("duration '{}' is too long", value);
+
+    let mut total = Duration::ZERO;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let amount: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid duration '{}'", value))?;
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            _ => return Err(format!("invalid unit '{}' in duration '{}'", c, value)),
+        };
+        total = amount
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(Duration::from_secs(seconds)))
+            .ok_or_else(too_long)?;
+        number.clear();
+    }
+    if !number.is_empty() || value.is_empty() {
+        return Err(format!("invalid duration '{}'", value));
+    }
+    Ok(total)
+}
+
+/// Returns the duration of a number of minutes
+///
+/// # Errors
+///
+/// Fails if the duration is too long to be represented.
+pub fn from_minutes(minutes: u64) -> Result<Duration, String> {
+    minutes
+        .checked_mul(60)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration of {} minutes is too long", minutes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_adapts_the_interval() {
+        let mut interval = AdaptiveInterval::new(Duration::from_secs(30), Duration::from_secs(100));
+        assert_eq!(interval.current(), Duration::from_secs(30));
+        assert_eq!(interval.next(false), Duration::from_secs(60));
+        assert_eq!(interval.next(false), Duration::from_secs(100));
+        assert_eq!(interval.next(false), Duration::from_secs(100));
+        assert_eq!(interval.next(true), Duration::from_secs(30));
+
+        let mut fixed = AdaptiveInterval::fixed(Duration::from_secs(300));
+        assert_eq!(fixed.next(false), Duration::from_secs(300));
+        assert_eq!(fixed.next(true), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn it_parses_durations() {
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1h2m3s"), Ok(Duration::from_secs(3723)));
+        assert_eq!(parse_duration(" 45s "), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("1m30").is_err());
+        assert!(parse_duration("-5").is_err());
+        assert!(parse_duration("307445734561825861").is_err());
+        assert!(parse_duration("5124095576030432h").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+        assert_eq!(
+            parse_duration("18446744073709551615s"),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
+}
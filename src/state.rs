@@ -0,0 +1,600 @@
+//! Download state kept in the directory of a thread
+
+use crate::{
+    journal::{find_directories, Journal},
+    Error,
+    Link,
+    Post,
+    Thread,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A file that has been downloaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Url the file was downloaded from
+    pub url:  String,
+    /// Name of the file in the directory of the thread
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Base64 encoded MD5 of the file, computed when it was saved
+    pub md5:  Option<String>,
+    /// Number of the post the file is attached to, when known
+    pub post: Option<u64>,
+    /// UNIX timestamp of the download
+    pub time: u64,
+}
+
+impl Record {
+    /// Returns the record of a link saved to `path`, downloaded now, with the
+    /// MD5 computed from the file
+    #[must_use]
+    pub fn new(link: &Link, path: &str, size: u64, md5: String) -> Self {
+        Self {
+            url: link.url.clone(),
+            path: path.to_owned(),
+            size,
+            md5: Some(md5),
+            post: link.post,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+        }
+    }
+}
+
+/// Returns the key naming the files kept for a thread in its directory, so
+/// that several threads can share a directory.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{state::thread_key, Thread};
+/// let thread = Thread {
+///     board: String::from("wg"),
+///     id:    6872254,
+/// };
+/// assert_eq!(thread_key("4chan", &thread), "4chan.wg.6872254");
+/// ```
+#[must_use]
+pub fn thread_key(site: &str, thread: &Thread) -> String {
+    format!("{}.{}.{}", site, thread.board, thread.id)
+}
+
+/// Gives the files kept for a single thread by older versions, named
+/// without its key, to the thread at `url`.
+///
+/// The files are only renamed if the copy of the thread is the one of `url`,
+/// or if there is no copy, since older versions saved each thread to its own
+/// directory.
+///
+/// # Errors
+///
+/// Fails if the files can't be read or renamed.
+pub fn migrate_legacy_files(directory: &Path, key: &str, url: &str) -> Result<(), Error> {
+    let legacy_copy = directory.join(SavedThread::LEGACY_FILE_NAME);
+    if let Some(saved) = SavedThread::load_path(&legacy_copy)? {
+        if saved.url != url {
+            return Ok(());
+        }
+        let copy = directory.join(SavedThread::file_name(key));
+        if !copy.exists() {
+            fs::rename(&legacy_copy, copy)?;
+        }
+    }
+    let legacy_state = directory.join(DownloadState::LEGACY_FILE_NAME);
+    let state = directory.join(DownloadState::file_name(key));
+    if legacy_state.exists() && !state.exists() {
+        fs::rename(legacy_state, state)?;
+    }
+    Ok(())
+}
+
+/// Files of a thread downloaded to its directory.
+///
+/// The records are appended to a JSON lines file in the directory, so they
+/// survive restarts. A file that has been recorded is never downloaded again,
+/// even if it was deleted from the directory since.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::state::{DownloadState, Record};
+/// use std::env;
+/// let directory = env::temp_dir();
+/// let mut state = DownloadState::open(&directory, "4chan.wg.6872254").unwrap();
+/// if !state.contains("//i.4cdn.org/wg/1489266570954.jpg") {
+///     println!("Not downloaded yet");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct DownloadState {
+    journal: Journal<Record>,
+    records: HashMap<String, Record>,
+}
+
+impl DownloadState {
+    /// Name of the file that held the state of the only thread of a
+    /// directory, in older versions
+    pub const LEGACY_FILE_NAME: &'static str = ".chan-downloader.jsonl";
+
+    /// Returns the name of the file holding the state of the thread with the
+    /// [`thread_key`], in its directory
+    #[must_use]
+    pub fn file_name(key: &str) -> String {
+        format!(".chan-downloader.{}.jsonl", key)
+    }
+
+    /// Returns true if the file is the state of a thread
+    #[must_use]
+    pub fn is_file_name(name: &str) -> bool {
+        name.starts_with(".chan-downloader.") && name.ends_with(".jsonl")
+    }
+
+    /// Loads the state of the thread with the [`thread_key`] in the
+    /// directory, which is empty if nothing has been downloaded there yet.
+    ///
+    /// Lines that can't be parsed, such as a line cut short by a crash, are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state file exists but can't be read.
+    pub fn open(directory: &Path, key: &str) -> Result<Self, Error> {
+        Self::open_path(directory.join(Self::file_name(key)))
+    }
+
+    /// Loads the states of all the threads of the directory
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory or a state file can't be read.
+    pub fn open_all(directory: &Path) -> Result<Vec<Self>, Error> {
+        let mut states = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if Self::is_file_name(&entry.file_name().to_string_lossy()) {
+                states.push(Self::open_path(entry.path())?);
+            }
+        }
+        Ok(states)
+    }
+
+    fn open_path(path: PathBuf) -> Result<Self, Error> {
+        let (journal, records) = Journal::open(path)?;
+        let records = records
+            .unwrap_or_default()
+            .into_iter()
+            .map(|record: Record| (record.url.clone(), record))
+            .collect();
+        Ok(Self { journal, records })
+    }
+
+    /// Returns the path of the state file
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        self.journal.path()
+    }
+
+    /// Returns true if the file at the url has been downloaded
+    #[must_use]
+    pub fn contains(&self, url: &str) -> bool {
+        self.records.contains_key(url)
+    }
+
+    /// Returns the record of the file at the url
+    #[must_use]
+    pub fn get(&self, url: &str) -> Option<&Record> {
+        self.records.get(url)
+    }
+
+    /// Returns the records of the downloaded files
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.values()
+    }
+
+    /// Returns the number of downloaded files
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns true if nothing has been downloaded
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Records a downloaded file, replacing any previous record of its url.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the record can't be appended to the state file.
+    pub fn insert(&mut self, record: Record) -> Result<(), Error> {
+        self.journal.append(&record)?;
+        self.records.insert(record.url.clone(), record);
+        Ok(())
+    }
+
+    /// Forgets the files at the urls, so they are downloaded again
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state file can't be written.
+    pub fn forget<'a>(&mut self, urls: impl IntoIterator<Item = &'a str>) -> Result<(), Error> {
+        let mut changed = false;
+        for url in urls {
+            changed |= self.records.remove(url).is_some();
+        }
+        if changed {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the state file with one line per downloaded file, dropping
+    /// the replaced records.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state file can't be written.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.journal.rewrite(self.records.values())
+    }
+}
+
+/// Copy of the posts of a thread, kept in its directory so it can be
+/// verified after the thread is gone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedThread {
+    /// Url of the thread
+    pub url:   String,
+    pub posts: Vec<Post>,
+}
+
+impl SavedThread {
+    /// Name of the copy of the only thread of a directory, in older versions
+    pub const LEGACY_FILE_NAME: &'static str = ".chan-downloader.thread.json";
+
+    /// Returns the name of the copy of the thread with the [`thread_key`], in
+    /// its directory
+    #[must_use]
+    pub fn file_name(key: &str) -> String {
+        format!(".chan-downloader.{}.thread.json", key)
+    }
+
+    /// Returns true if the file is the copy of a thread
+    #[must_use]
+    pub fn is_file_name(name: &str) -> bool {
+        name.starts_with(".chan-downloader.") && name.ends_with(".thread.json")
+    }
+
+    /// Loads the copy of the thread with the [`thread_key`] saved in the
+    /// directory, if any
+    ///
+    /// # Errors
+    ///
+    /// Fails if the copy exists but can't be read or parsed.
+    pub fn load(directory: &Path, key: &str) -> Result<Option<Self>, Error> {
+        Self::load_path(&directory.join(Self::file_name(key)))
+    }
+
+    /// Loads the copies of all the threads saved in the directory
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory or a copy can't be read or parsed.
+    pub fn load_all(directory: &Path) -> Result<Vec<Self>, Error> {
+        let mut copies = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if Self::is_file_name(&entry.file_name().to_string_lossy()) {
+                copies.extend(Self::load_path(&entry.path())?);
+            }
+        }
+        copies.sort_by(|a, b| a.url.cmp(&b.url));
+        Ok(copies)
+    }
+
+    fn load_path(path: &Path) -> Result<Option<Self>, Error> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves the copy of the thread with the [`thread_key`] to the directory,
+    /// replacing the previous one
+    ///
+    /// # Errors
+    ///
+    /// Fails if the copy can't be written.
+    pub fn save(&self, directory: &Path, key: &str) -> Result<(), Error> {
+        let path = directory.join(Self::file_name(key));
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_string(self)?)?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+/// Directory of a thread, relative to the output directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ThreadDirectory {
+    /// Url of the thread page
+    url:  String,
+    path: PathBuf,
+}
+
+/// Index of the thread directories under an output directory, by thread url.
+///
+/// A thread keeps the directory it was first saved to, even when the
+/// directory template changes or when the subject it was named after is
+/// edited. An output directory without an index, such as one filled by an
+/// older version, gets indexed from the copies of its threads.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::state::ThreadIndex;
+/// use std::env;
+/// let output = env::current_dir().unwrap().join("downloads");
+/// if let Ok(index) = ThreadIndex::open(&output) {
+///     if let Some(directory) = index.get("https://boards.4chan.org/wg/thread/6872254") {
+///         println!("Saved to {}", directory.display());
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ThreadIndex {
+    root:        PathBuf,
+    journal:     Journal<ThreadDirectory>,
+    directories: HashMap<String, PathBuf>,
+}
+
+impl ThreadIndex {
+    /// Name of the index, in the output directory
+    pub const FILE_NAME: &'static str = ".chan-downloader-threads.jsonl";
+
+    /// Loads the index of the output directory, building it from the saved
+    /// threads the first time
+    ///
+    /// # Errors
+    ///
+    /// Fails if the index exists but can't be read, or can't be created.
+    pub fn open(root: &Path) -> Result<Self, Error> {
+        let (journal, entries) = Journal::open(root.join(Self::FILE_NAME))?;
+        let mut index = Self {
+            root: root.to_owned(),
+            journal,
+            directories: HashMap::new(),
+        };
+        match entries {
+            Some(entries) =>
+                for entry in entries {
+                    index.directories.insert(entry.url, entry.path);
+                },
+            None => find_directories(root, &SavedThread::is_file_name, &mut |directory| {
+                match SavedThread::load_all(directory) {
+                    Ok(copies) =>
+                        for saved in copies {
+                            index.insert(&saved.url, directory)?;
+                        },
+                    Err(err) => warn!("Failed to index {}: {}", directory.display(), err),
+                }
+                Ok(())
+            })?,
+        }
+        Ok(index)
+    }
+
+    /// Returns the directory of the thread, if it still exists
+    #[must_use]
+    pub fn get(&self, url: &str) -> Option<PathBuf> {
+        let directory = self.root.join(self.directories.get(url)?);
+        directory.is_dir().then_some(directory)
+    }
+
+    /// Records the directory of the thread
+    ///
+    /// # Errors
+    ///
+    /// Fails if the entry can't be appended to the index.
+    pub fn insert(&mut self, url: &str, directory: &Path) -> Result<(), Error> {
+        let path = directory.strip_prefix(&self.root).unwrap_or(directory);
+        if self.directories.get(url).map(PathBuf::as_path) == Some(path) {
+            return Ok(());
+        }
+        let entry = ThreadDirectory {
+            url:  url.to_owned(),
+            path: path.to_owned(),
+        };
+        self.journal.append(&entry)?;
+        self.directories.insert(entry.url, entry.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::empty_directory;
+
+    const KEY: &str = "4chan.po.570368";
+
+    fn record(url: &str, path: &str) -> Record {
+        Record {
+            url:  url.to_owned(),
+            path: path.to_owned(),
+            size: 1024,
+            md5:  Some(String::from("Fb1y+3XGvZPxxFDPNLzvHA==")),
+            post: Some(570368),
+            time: 1489266570,
+        }
+    }
+
+    #[test]
+    fn it_keeps_records_between_runs() {
+        let directory = empty_directory("chan-downloader-state");
+        let mut state = DownloadState::open(&directory, KEY).unwrap();
+        assert!(state.is_empty());
+
+        let url = "//i.4cdn.org/wg/1489266570954.jpg";
+        state.insert(record(url, "1489266570954.jpg")).unwrap();
+        state.insert(record("//i.4cdn.org/wg/2.png", "2.png")).unwrap();
+        assert!(state.contains(url));
+        drop(state);
+
+        let state = DownloadState::open(&directory, KEY).unwrap();
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.get(url), Some(&record(url, "1489266570954.jpg")));
+        assert!(!state.contains("//i.4cdn.org/wg/3.gif"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_skips_broken_lines() {
+        let directory = empty_directory("chan-downloader-state-broken");
+        let url = "//i.4cdn.org/wg/1.jpg";
+        let line = serde_json::to_string(&record(url, "1.jpg")).unwrap();
+        fs::write(
+            directory.join(DownloadState::file_name(KEY)),
+            format!("{}\n\n{{\"url\": \"//i.4cdn.org/wg/2", line),
+        )
+        .unwrap();
+
+        let mut state = DownloadState::open(&directory, KEY).unwrap();
+        assert_eq!(state.len(), 1);
+        assert!(state.contains(url));
+
+        state.insert(record("//i.4cdn.org/wg/3.jpg", "3.jpg")).unwrap();
+        let state = DownloadState::open(&directory, KEY).unwrap();
+        assert_eq!(state.len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_compacts_replaced_records() {
+        let directory = empty_directory("chan-downloader-state-compact");
+        let url = "//i.4cdn.org/wg/1.jpg";
+        let mut state = DownloadState::open(&directory, KEY).unwrap();
+        state.insert(record(url, "1.jpg")).unwrap();
+        state.insert(record(url, "renamed.jpg")).unwrap();
+        state.compact().unwrap();
+        state.insert(record("//i.4cdn.org/wg/2.jpg", "2.jpg")).unwrap();
+
+        let content = fs::read_to_string(state.path()).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let mut state = DownloadState::open(&directory, KEY).unwrap();
+        assert_eq!(state.get(url).unwrap().path, "renamed.jpg");
+
+        state.forget([url]).unwrap();
+        let state = DownloadState::open(&directory, KEY).unwrap();
+        assert_eq!(state.len(), 1);
+        assert!(!state.contains(url));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_saves_threads() {
+        let directory = empty_directory("chan-downloader-saved-thread");
+        assert_eq!(SavedThread::load(&directory, KEY).unwrap(), None);
+
+        let content = r#"{"posts": [{"no": 570368, "time": 1489266570, "tim": 1489266570954,
+            "filename": "stickyop", "ext": ".jpg", "md5": "Fb1y+3XGvZPxxFDPNLzvHA=="}]}"#;
+        let thread = SavedThread {
+            url:   String::from("https://boards.4chan.org/po/thread/570368"),
+            posts: crate::get_posts(content).unwrap(),
+        };
+        thread.save(&directory, KEY).unwrap();
+        assert_eq!(SavedThread::load(&directory, KEY).unwrap(), Some(thread));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_the_threads_of_a_directory_apart() {
+        let directory = empty_directory("chan-downloader-shared-directory");
+        let legacy = "https://boards.4chan.org/po/thread/570368";
+        let other = "https://boards.4chan.org/po/thread/570369";
+        fs::write(
+            directory.join(DownloadState::LEGACY_FILE_NAME),
+            serde_json::to_string(&record("//i.4cdn.org/po/1.jpg", "1.jpg")).unwrap(),
+        )
+        .unwrap();
+        let copy = |url: &str| SavedThread {
+            url:   url.to_owned(),
+            posts: Vec::new(),
+        };
+        fs::write(
+            directory.join(SavedThread::LEGACY_FILE_NAME),
+            serde_json::to_string(&copy(legacy)).unwrap(),
+        )
+        .unwrap();
+
+        // The legacy files belong to the thread of the legacy copy only
+        migrate_legacy_files(&directory, "4chan.po.570369", other).unwrap();
+        assert!(DownloadState::open(&directory, "4chan.po.570369")
+            .unwrap()
+            .is_empty());
+        migrate_legacy_files(&directory, KEY, legacy).unwrap();
+        assert!(!directory.join(DownloadState::LEGACY_FILE_NAME).exists());
+        assert_eq!(SavedThread::load(&directory, KEY).unwrap(), Some(copy(legacy)));
+        assert!(DownloadState::open(&directory, KEY)
+            .unwrap()
+            .contains("//i.4cdn.org/po/1.jpg"));
+
+        copy(other).save(&directory, "4chan.po.570369").unwrap();
+        let mut state = DownloadState::open(&directory, "4chan.po.570369").unwrap();
+        state.insert(record("//i.4cdn.org/po/2.jpg", "1.jpg")).unwrap();
+        assert_eq!(DownloadState::open(&directory, KEY).unwrap().len(), 1);
+        assert_eq!(DownloadState::open_all(&directory).unwrap().len(), 2);
+        assert_eq!(SavedThread::load_all(&directory).unwrap(), [
+            copy(legacy),
+            copy(other)
+        ]);
+
+        let index = ThreadIndex::open(&directory).unwrap();
+        assert_eq!(index.get(legacy), Some(directory.clone()));
+        assert_eq!(index.get(other), Some(directory.clone()));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn it_indexes_thread_directories() {
+        let root = empty_directory("chan-downloader-thread-index");
+        let url = "https://boards.4chan.org/po/thread/570368";
+        let saved = root.join("po/570368");
+        fs::create_dir_all(&saved).unwrap();
+        SavedThread {
+            url:   url.to_owned(),
+            posts: Vec::new(),
+        }
+        .save(&saved, KEY)
+        .unwrap();
+
+        let mut index = ThreadIndex::open(&root).unwrap();
+        assert_eq!(index.get(url), Some(saved.clone()));
+        let other = "https://boards.4chan.org/wg/thread/6872254";
+        let renamed = root.join("4chan/wg/6872254-wallpaper-general");
+        assert_eq!(index.get(other), None);
+        index.insert(other, &renamed).unwrap();
+        assert_eq!(index.get(other), None);
+        fs::create_dir_all(&renamed).unwrap();
+        assert_eq!(index.get(other), Some(renamed.clone()));
+
+        let index = ThreadIndex::open(&root).unwrap();
+        assert_eq!(index.get(url), Some(saved));
+        assert_eq!(index.get(other), Some(renamed));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,526 @@
+//! Names of the saved files, rendered from the metadata of their post
+
+use crate::{
+    post::{File, Post},
+    sanitize_filename,
+    Thread,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime,
+};
+use std::{fmt, path::PathBuf, str::FromStr};
+
+/// Longest filename accepted by most filesystems, in bytes
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Longest `{subject_slug}`, in bytes
+const MAX_SLUG_LENGTH: usize = 50;
+
+/// Template of the name a file is saved under, such as `{no}_{original}.{ext}`.
+///
+/// The placeholders are:
+/// - `{board}` and `{thread}`: board and number of the thread
+/// - `{no}`: number of the post
+/// - `{tim}`: name of the file on the server, a timestamp
+/// - `{original}`: filename given by the poster, without the extension
+/// - `{ext}`: extension, without the dot
+/// - `{md5}`: MD5 given by the API, in hexadecimal
+/// - `{index}`: position of the file in the thread, from 1
+/// - `{w}` and `{h}`: width and height of the image or video
+/// - `{date}`: date of the post as `%Y-%m-%d`, or in any [`strftime`] format
+///   given after a colon, such as `{date:%Y%m%d-%H%M}`. Dates are in UTC.
+///
+/// `{{` and `}}` are literal braces. The rendered name is sanitized for the
+/// filesystem: path separators and reserved characters are replaced, leading
+/// dots are removed, and long names are shortened.
+///
+/// [`strftime`]: chrono::format::strftime
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::template::FilenameTemplate;
+/// let template: FilenameTemplate = "{date:%Y%m%d} {no} {w}x{h}.{ext}".parse().unwrap();
+/// assert_eq!(template.to_string(), "{date:%Y%m%d} {no} {w}x{h}.{ext}");
+/// assert!("{size}".parse::<FilenameTemplate>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    source: String,
+    parts:  Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Site,
+    Board,
+    Thread,
+    Subject,
+    SubjectSlug,
+    No,
+    Tim,
+    Original,
+    Ext,
+    Md5,
+    Index,
+    Width,
+    Height,
+    Date(String),
+}
+
+/// What a file name is rendered from
+#[derive(Debug, Clone, Copy)]
+pub struct FileContext<'a> {
+    pub thread: &'a Thread,
+    pub post:   &'a Post,
+    pub file:   &'a File,
+    /// Position of the file in the thread, from 1
+    pub index:  usize,
+}
+
+impl FilenameTemplate {
+    /// Returns the template naming the files as on the server, `{tim}.{ext}`
+    #[must_use]
+    pub fn server() -> Self {
+        Self::from_str("{tim}.{ext}").expect("valid template")
+    }
+
+    /// Returns the template naming the files after the filename given by the
+    /// poster, `{original}.{ext}`
+    #[must_use]
+    pub fn original() -> Self {
+        Self::from_str("{original}.{ext}").expect("valid template")
+    }
+
+    /// Returns the name of the file, sanitized for the filesystem.
+    ///
+    /// Falls back to the name of the file on the server when nothing is left
+    /// of the name after sanitizing.
+    #[must_use]
+    pub fn render(&self, context: &FileContext<'_>) -> String {
+        let FileContext { thread, post, file, index } = *context;
+        let mut name = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => text.clone(),
+                Part::Board => thread.board.clone(),
+                Part::Thread => thread.id.to_string(),
+                Part::No => post.no.to_string(),
+                Part::Tim => file.tim.to_string(),
+                Part::Original => file.filename.clone(),
+                Part::Ext => file.ext.trim_start_matches('.').to_owned(),
+                Part::Md5 => base64::decode(&file.md5)
+                    .map(|md5| md5.iter().map(|byte| format!("{:02x}", byte)).collect())
+                    .unwrap_or_default(),
+                Part::Index => index.to_string(),
+                Part::Width => file.w.to_string(),
+                Part::Height => file.h.to_string(),
+                Part::Date(format) => format_date(post.time, format),
+                // Only parsed in directory templates
+                Part::Site | Part::Subject | Part::SubjectSlug => String::new(),
+            };
+            name.push_str(&value);
+        }
+
+        let name = sanitize_filename(&name);
+        let name = name.trim().trim_start_matches('.');
+        if name.is_empty() {
+            file.server_name()
+        } else {
+            shorten(name, &file.ext)
+        }
+    }
+}
+
+/// Template of the directory a thread is saved to, relative to the output
+/// directory, such as `{site}/{board}/{thread}-{subject_slug}`.
+///
+/// The placeholders are:
+/// - `{site}`: name of the imageboard, such as `4chan`
+/// - `{board}` and `{thread}`: board and number of the thread
+/// - `{subject}`: subject of the thread
+/// - `{subject_slug}`: subject in lower case with its words joined by dashes,
+///   or the beginning of the OP comment when there is no subject
+/// - `{date}`: date of the OP, formatted as in [`FilenameTemplate`]
+///
+/// `/` separates the directories. Each directory is sanitized like a
+/// filename, and the empty ones are left out, so an empty template saves
+/// every thread directly to the output directory.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{
+///     template::{DirectoryTemplate, ThreadContext},
+///     Thread,
+/// };
+/// use std::path::Path;
+/// let template: DirectoryTemplate = "{site}/{board}/{thread}-{subject_slug}".parse().unwrap();
+/// let thread = Thread {
+///     board: String::from("wg"),
+///     id:    6872254,
+/// };
+/// let context = ThreadContext {
+///     site:   "4chan",
+///     thread: &thread,
+///     op:     None,
+/// };
+/// assert!(template.needs_op());
+/// assert_eq!(template.render(&context), Path::new("4chan/wg/6872254"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryTemplate {
+    source: String,
+    parts:  Vec<Part>,
+}
+
+/// What a thread directory is rendered from
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadContext<'a> {
+    /// Name of the imageboard
+    pub site:   &'a str,
+    pub thread: &'a Thread,
+    /// OP of the thread, when known
+    pub op:     Option<&'a Post>,
+}
+
+impl DirectoryTemplate {
+    /// Returns true if the template uses the OP of the thread, which then
+    /// has to be loaded before the directory is known
+    #[must_use]
+    pub fn needs_op(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Subject | Part::SubjectSlug | Part::Date(_)))
+    }
+
+    /// Returns the directory of the thread, relative to the output
+    /// directory. The placeholders using the OP are empty without it.
+    #[must_use]
+    pub fn render(&self, context: &ThreadContext<'_>) -> PathBuf {
+        let ThreadContext { site, thread, op } = *context;
+        let mut path = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    path.push_str(text);
+                    continue;
+                },
+                Part::Site => site.to_owned(),
+                Part::Board => thread.board.clone(),
+                Part::Thread => thread.id.to_string(),
+                Part::Subject => op.and_then(Post::subject_text).unwrap_or_default(),
+                Part::SubjectSlug => op.map(subject_slug).unwrap_or_default(),
+                Part::Date(format) => op.map(|op| format_date(op.time, format)).unwrap_or_default(),
+                // Only parsed in filename templates
+                _ => String::new(),
+            };
+            // A value can't add directories
+            path.push_str(&sanitize_filename(&value));
+        }
+        path.split('/')
+            .map(|directory| {
+                let directory = sanitize_filename(directory);
+                directory
+                    .trim()
+                    .trim_start_matches('.')
+                    .trim_end_matches(|c: char| c == '-' || c == '_' || c == '.' || c.is_whitespace())
+                    .to_owned()
+            })
+            .filter(|directory| !directory.is_empty())
+            .collect()
+    }
+}
+
+impl Default for DirectoryTemplate {
+    /// Returns the template saving the threads to `{board}/{thread}`
+    fn default() -> Self {
+        Self::from_str("{board}/{thread}").expect("valid template")
+    }
+}
+
+impl FromStr for DirectoryTemplate {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let parts = parse_parts(source, &[
+            "site",
+            "board",
+            "thread",
+            "subject",
+            "subject_slug",
+            "date",
+        ])?;
+        Ok(Self {
+            source: source.to_owned(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for DirectoryTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Returns the subject of the post, or else the beginning of its comment, in
+/// lower case with its words joined by dashes
+fn subject_slug(op: &Post) -> String {
+    let text = op
+        .subject_text()
+        .filter(|subject| !subject.trim().is_empty())
+        .or_else(|| op.comment_text())
+        .unwrap_or_default()
+        .to_lowercase();
+    let mut slug = String::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if slug.is_empty() {
+            let mut end = word.len().min(MAX_SLUG_LENGTH);
+            while !word.is_char_boundary(end) {
+                end -= 1;
+            }
+            slug.push_str(&word[..end]);
+        } else if slug.len() + 1 + word.len() <= MAX_SLUG_LENGTH {
+            slug.push('-');
+            slug.push_str(word);
+        } else {
+            break;
+        }
+    }
+    slug
+}
+
+/// Returns the date of the UNIX timestamp in UTC, in the strftime format
+fn format_date(time: i64, format: &str) -> String {
+    DateTime::from_timestamp(time, 0)
+        .map(|date| date.format(format).to_string())
+        .unwrap_or_default()
+}
+
+/// Cuts the name to the longest length accepted by the filesystem, keeping
+/// the extension
+fn shorten(name: &str, ext: &str) -> String {
+    if name.len() <= MAX_FILENAME_LENGTH {
+        return name.to_owned();
+    }
+    let (stem, ext) = match name.strip_suffix(ext) {
+        Some(stem) if ext.len() < MAX_FILENAME_LENGTH => (stem, ext),
+        _ => (name, ""),
+    };
+    let mut end = MAX_FILENAME_LENGTH - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let parts = parse_parts(source, &[
+            "board", "thread", "no", "tim", "original", "ext", "md5", "index", "w", "h", "date",
+        ])?;
+        if parts.is_empty() {
+            return Err(String::from("empty filename template"));
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            parts,
+        })
+    }
+}
+
+/// Splits the template into text and the placeholders with the given names
+fn parse_parts(source: &str, placeholders: &[&str]) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                text.push('{');
+            },
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                text.push('}');
+            },
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("unclosed placeholder in '{}'", source))?;
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(parse_placeholder(&rest[..end], placeholders)?);
+                chars = rest[end + 1..].chars();
+            },
+            '}' => return Err(format!("unmatched '}}' in '{}', use '}}}}'", source)),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+fn parse_placeholder(placeholder: &str, placeholders: &[&str]) -> Result<Part, String> {
+    let (name, format) = match placeholder.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (placeholder, None),
+    };
+    if !placeholders.contains(&name) {
+        return Err(format!("unknown placeholder '{{{}}}'", placeholder));
+    }
+    let part = match name {
+        "site" => Part::Site,
+        "board" => Part::Board,
+        "thread" => Part::Thread,
+        "subject" => Part::Subject,
+        "subject_slug" => Part::SubjectSlug,
+        "no" => Part::No,
+        "tim" => Part::Tim,
+        "original" => Part::Original,
+        "ext" => Part::Ext,
+        "md5" => Part::Md5,
+        "index" => Part::Index,
+        "w" => Part::Width,
+        "h" => Part::Height,
+        "date" => {
+            let format = format.unwrap_or("%Y-%m-%d");
+            if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format!("invalid date format '{}'", format));
+            }
+            return Ok(Part::Date(format.to_owned()));
+        },
+        _ => unreachable!("placeholder '{}' is not handled", name),
+    };
+    match format {
+        Some(_) => Err(format!("'{{{}}}' doesn't take a format", name)),
+        None => Ok(part),
+    }
+}
+
+impl fmt::Display for FilenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn render(template: &str) -> String {
+        let thread = Thread {
+            board: String::from("wg"),
+            id:    6872254,
+        };
+        let post: Post = serde_json::from_str(
+            r#"{"no": 6872255, "time": 1489266570, "name": null, "tripcode": null, "id": null,
+                "subject": null, "comment": null, "file": null}"#,
+        )
+        .unwrap();
+        let file = File {
+            tim:      1489266570954,
+            filename: String::from("a/b: c"),
+            ext:      String::from(".jpg"),
+            fsize:    5,
+            md5:      String::from("eIBaIhqYjnnvP0LXxb/UGA=="),
+            w:        1920,
+            h:        1080,
+            tn_w:     250,
+            tn_h:     140,
+            spoiler:  false,
+            deleted:  false,
+        };
+        let context = FileContext {
+            thread: &thread,
+            post:   &post,
+            file:   &file,
+            index:  2,
+        };
+        template.parse::<FilenameTemplate>().unwrap().render(&context)
+    }
+
+    #[test]
+    fn it_renders_templates() {
+        assert_eq!(render("{tim}.{ext}"), "1489266570954.jpg");
+        assert_eq!(render("{original}.{ext}"), "a_b_ c.jpg");
+        assert_eq!(
+            render("{board}-{thread}-{no}-{index} {w}x{h}.{ext}"),
+            "wg-6872254-6872255-2 1920x1080.jpg"
+        );
+        assert_eq!(render("{md5}"), "78805a221a988e79ef3f42d7c5bfd418");
+        assert_eq!(render("{date} {tim}"), "2017-03-11 1489266570954");
+        assert_eq!(render("{date:%Y/%m/%d %H%M}"), "2017_03_11 2109");
+        assert_eq!(render("{{{no}}}"), "{6872255}");
+        assert_eq!(render("..{no}"), "6872255");
+        assert_eq!(render(" . "), "1489266570954.jpg");
+
+        let long = render(&format!("{}.{{ext}}", "a".repeat(300)));
+        assert_eq!(long.len(), MAX_FILENAME_LENGTH);
+        assert!(long.ends_with("a.jpg"));
+    }
+
+    #[test]
+    fn it_renders_directory_templates() {
+        let thread = Thread {
+            board: String::from("wg"),
+            id:    6872254,
+        };
+        let op: Post = serde_json::from_str(
+            r#"{"no": 6872254, "time": 1489266570, "name": null, "tripcode": null, "id": null,
+                "subject": "Wallpaper General &amp; Requests /wg/ #42", "comment": null,
+                "file": null}"#,
+        )
+        .unwrap();
+        let render = |template: &str, op| {
+            let context = ThreadContext {
+                site: "4chan",
+                thread: &thread,
+                op,
+            };
+            template.parse::<DirectoryTemplate>().unwrap().render(&context)
+        };
+        let full = "{site}/{board}/{thread}-{subject_slug}";
+        assert_eq!(
+            render(full, Some(&op)),
+            Path::new("4chan/wg/6872254-wallpaper-general-requests-wg-42")
+        );
+        assert_eq!(render(full, None), Path::new("4chan/wg/6872254"));
+        assert_eq!(
+            render("{date:%Y}/{subject}", Some(&op)),
+            Path::new("2017/Wallpaper General & Requests _wg_ #42")
+        );
+        assert_eq!(render("{board}/../{thread}", None), Path::new("wg/6872254"));
+        assert_eq!(render("", None), Path::new(""));
+        assert_eq!(
+            DirectoryTemplate::default().render(&ThreadContext {
+                site:   "4chan",
+                thread: &thread,
+                op:     None,
+            }),
+            Path::new("wg/6872254")
+        );
+        assert!("{no}".parse::<DirectoryTemplate>().is_err());
+        assert!(!DirectoryTemplate::default().needs_op());
+    }
+
+    #[test]
+    fn it_rejects_invalid_templates() {
+        for template in ["", "{size}", "{no", "no}", "{date:%Q}", "{no:05}", "{subject}"] {
+            assert!(template.parse::<FilenameTemplate>().is_err(), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Audit of the directory of a downloaded thread
+
+use crate::{file_md5, state::DownloadState, Error, Link};
+use std::{collections::HashSet, fmt, path::Path};
+use tokio::fs;
+
+/// Problem found with a file of the thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The file is not in the directory
+    Missing,
+    /// The file doesn't have the size given by the API
+    Size { expected: u64, actual: u64 },
+    /// The file doesn't have the MD5 given by the API
+    Md5 { expected: String, actual: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::Size { expected, actual } => {
+                write!(f, "{} bytes instead of {}", actual, expected)
+            },
+            Self::Md5 { expected, actual } => write!(f, "MD5 {} instead of {}", actual, expected),
+        }
+    }
+}
+
+/// Result of [`verify_directory`]
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of files of the thread that were checked
+    pub checked:    usize,
+    /// Files of the thread that are missing or corrupt, named as they are in
+    /// the directory
+    pub problems:   Vec<(Link, Problem)>,
+    /// Files of the thread that were downloaded, then deleted from the
+    /// directory, most likely on purpose
+    pub deleted:    Vec<Link>,
+    /// Files of the directory that don't belong to the thread
+    pub unexpected: Vec<String>,
+}
+
+impl Report {
+    /// Returns true if every file of the thread that wasn't deleted on purpose
+    /// is in the directory, and nothing else is
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Compares the files of a thread directory with the links of the thread.
+///
+/// A file is looked for under the name recorded in the download state, or
+/// under the name of its link if it was never recorded. A recorded file that
+/// is gone is reported as deleted rather than missing. Its size and MD5 are
+/// compared with the ones given by the API, when known. Only a recorded file
+/// is reported as corrupt: a file with the name of a link that was never
+/// recorded but another content is another file, so the link is missing. The
+/// files kept by `chan_downloader` itself, such as the download state and the
+/// partial downloads, are not reported as unexpected.
+///
+/// # Errors
+///
+/// Fails if the directory can't be read.
+///
+/// # Examples
+///
+/// ```
+/// use chan_downloader::{state::DownloadState, verify::verify_directory};
+/// use std::env;
+/// let directory = env::current_dir().unwrap().join("downloads/wg/6872254");
+/// async {
+///     let state = DownloadState::open(&directory, "4chan.wg.6872254").unwrap();
+///     let report = verify_directory(&directory, &[], &state).await.unwrap();
+///     for (link, problem) in &report.problems {
+///         println!("{}: {}", link.name, problem);
+///     }
+/// };
+/// ```
+pub async fn verify_directory(
+    directory: &Path,
+    links: &[Link],
+    state: &DownloadState,
+) -> Result<Report, Error> {
+    let mut report = Report::default();
+    let mut expected_names = HashSet::new();
+    for link in links {
+        let mut link = link.clone();
+        let record = state.get(&link.url);
+        if let Some(record) = record {
+            link.name = record.path.clone();
+        }
+        report.checked += 1;
+        match check_file(&directory.join(&link.name), &link).await? {
+            Some(Problem::Missing) if record.is_some() => report.deleted.push(link),
+            Some(_) if record.is_none() => report.problems.push((link, Problem::Missing)),
+            Some(problem) => {
+                expected_names.insert(link.name.clone());
+                report.problems.push((link, problem));
+            },
+            None => {
+                expected_names.insert(link.name.clone());
+            },
+        }
+    }
+
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_file()
+            && !name.starts_with(".chan-downloader")
+            && !name.ends_with(".part")
+            && !name.ends_with(".part.validator")
+            && !expected_names.contains(&name)
+        {
+            report.unexpected.push(name);
+        }
+    }
+    report.unexpected.sort();
+    Ok(report)
+}
+
+/// Returns the problem of the file at `path`, if any
+async fn check_file(path: &Path, link: &Link) -> Result<Option<Problem>, Error> {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Some(Problem::Missing)),
+        Err(err) => return Err(err.into()),
+    };
+    let file = match &link.file {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    if file.fsize > 0 && metadata.len() != file.fsize {
+        return Ok(Some(Problem::Size {
+            expected: file.fsize,
+            actual:   metadata.len(),
+        }));
+    }
+    if !file.md5.is_empty() {
+        let actual = file_md5(&path.to_string_lossy()).await?;
+        if actual != file.md5 {
+            return Ok(Some(Problem::Md5 {
+                expected: file.md5.clone(),
+                actual,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{post::File, state::Record, tests::empty_directory};
+    use md5::{Digest, Md5};
+    use std::fs;
+
+    fn link(name: &str, content: &[u8]) -> Link {
+        Link {
+            url:  format!("//i.4cdn.org/wg/{}", name),
+            name: name.to_owned(),
+            post: Some(1),
+            file: Some(File {
+                tim:      1,
+                filename: String::from("file"),
+                ext:      String::from(".jpg"),
+                fsize:    content.len() as u64,
+                md5:      base64::encode(Md5::digest(content)),
+                w:        0,
+                h:        0,
+                tn_w:     0,
+                tn_h:     0,
+                spoiler:  false,
+                deleted:  false,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_verifies_directories() {
+        let directory = empty_directory("chan-downloader-verify");
+        let links = [
+            link("1.jpg", b"image"),
+            link("2.jpg", b"image"),
+            link("3.jpg", b"image"),
+            link("4.jpg", b"image"),
+            link("5.jpg", b"image"),
+            link("6.jpg", b"image"),
+            link("7.jpg", b"image"),
+        ];
+        fs::write(directory.join("1.jpg"), "image").unwrap();
+        fs::write(directory.join("3.jpg"), "imag").unwrap();
+        fs::write(directory.join("4.jpg"), "IMAGE").unwrap();
+        fs::write(directory.join("renamed.jpg"), "image").unwrap();
+        fs::write(directory.join("notes.txt"), "").unwrap();
+        fs::write(directory.join("6.jpg.part"), "ima").unwrap();
+        fs::write(directory.join("6.jpg.part.validator"), "\"etag\"").unwrap();
+        // Another file, which has the name of a file never downloaded
+        fs::write(directory.join("7.jpg"), "other").unwrap();
+
+        let mut state = DownloadState::open(&directory, "4chan.wg.1").unwrap();
+        let md5 = links[4].file.as_ref().unwrap().md5.clone();
+        state
+            .insert(Record::new(&links[4], "renamed.jpg", 5, md5.clone()))
+            .unwrap();
+        state
+            .insert(Record::new(&links[5], "6.jpg", 5, md5.clone()))
+            .unwrap();
+        for link in &links[2..4] {
+            state
+                .insert(Record::new(link, &link.name, 5, md5.clone()))
+                .unwrap();
+        }
+
+        let report = verify_directory(&directory, &links, &state).await.unwrap();
+        assert_eq!(report.checked, 7);
+        let problems: Vec<_> = report
+            .problems
+            .iter()
+            .map(|(link, problem)| (link.name.as_str(), problem))
+            .collect();
+        assert_eq!(problems.len(), 4);
+        assert_eq!(problems[0], ("2.jpg", &Problem::Missing));
+        assert_eq!(problems[1], ("3.jpg", &Problem::Size { expected: 5, actual: 4 }));
+        assert!(matches!(problems[2], ("4.jpg", Problem::Md5 { .. })));
+        assert_eq!(problems[3], ("7.jpg", &Problem::Missing));
+        assert_eq!(report.deleted.len(), 1);
+        assert_eq!(report.deleted[0].name, "6.jpg");
+        assert_eq!(report.unexpected, ["7.jpg", "notes.txt"]);
+        assert!(!report.is_ok());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}